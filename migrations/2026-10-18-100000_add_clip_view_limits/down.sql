@@ -0,0 +1,2 @@
+ALTER TABLE clips DROP COLUMN views;
+ALTER TABLE clips DROP COLUMN max_views;
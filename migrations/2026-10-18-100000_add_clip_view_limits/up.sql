@@ -0,0 +1,2 @@
+ALTER TABLE clips ADD COLUMN max_views INTEGER;
+ALTER TABLE clips ADD COLUMN views INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE clips DROP COLUMN password_hash;
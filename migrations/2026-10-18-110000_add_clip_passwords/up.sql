@@ -0,0 +1 @@
+ALTER TABLE clips ADD COLUMN password_hash TEXT;
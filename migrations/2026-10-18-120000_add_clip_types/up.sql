@@ -0,0 +1 @@
+ALTER TABLE clips ADD COLUMN clip_type TEXT NOT NULL DEFAULT 'url';
@@ -0,0 +1,2 @@
+ALTER TABLE clips DROP COLUMN content_type;
+ALTER TABLE clips DROP COLUMN file_size;
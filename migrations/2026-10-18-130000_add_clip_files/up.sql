@@ -0,0 +1,2 @@
+ALTER TABLE clips ADD COLUMN file_size BIGINT;
+ALTER TABLE clips ADD COLUMN content_type TEXT;
@@ -0,0 +1 @@
+ALTER TABLE clips ADD COLUMN file_name TEXT;
@@ -0,0 +1,3 @@
+DROP TABLE clips_archive;
+DROP INDEX clips_deleted_at;
+ALTER TABLE clips DROP COLUMN deleted_at;
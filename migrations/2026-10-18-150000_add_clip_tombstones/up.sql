@@ -0,0 +1,18 @@
+ALTER TABLE clips ADD COLUMN deleted_at TIMESTAMP;
+CREATE INDEX clips_deleted_at ON clips (deleted_at) WHERE deleted_at IS NOT NULL;
+
+CREATE TABLE clips_archive (
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL,
+    code TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP,
+    max_views INTEGER,
+    views INTEGER NOT NULL,
+    clip_type TEXT NOT NULL,
+    file_size BIGINT,
+    content_type TEXT,
+    file_name TEXT,
+    deleted_at TIMESTAMP NOT NULL,
+    archived_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
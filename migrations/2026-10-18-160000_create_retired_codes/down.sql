@@ -0,0 +1 @@
+DROP TABLE retired_codes;
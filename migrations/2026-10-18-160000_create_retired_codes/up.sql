@@ -0,0 +1,6 @@
+CREATE TABLE retired_codes (
+    code TEXT PRIMARY KEY,
+    retired_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX retired_codes_retired_at ON retired_codes (retired_at);
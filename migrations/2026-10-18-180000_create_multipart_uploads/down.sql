@@ -0,0 +1 @@
+DROP TABLE multipart_uploads;
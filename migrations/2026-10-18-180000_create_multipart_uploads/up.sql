@@ -0,0 +1,10 @@
+-- The sizes multipart uploads were started with, so their parts can be held to them
+CREATE TABLE multipart_uploads (
+    upload_id TEXT PRIMARY KEY,
+    object_key TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    part_size BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX multipart_uploads_created_at ON multipart_uploads (created_at);
@@ -0,0 +1,5 @@
+-- When each exclusive maintenance job last ran on any instance
+CREATE TABLE job_runs (
+    name TEXT PRIMARY KEY,
+    last_run TIMESTAMP NOT NULL
+);
@@ -2,35 +2,50 @@ mod models;
 mod schema;
 mod utils;
 
-use clokwerk::{Scheduler, TimeUnits};
-use regex::Regex;
-use rocket::http::{Header, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::response::status::Custom;
+use rocket::response::Redirect;
 use rocket::State;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
-use utils::files::{create_storage_client, put_object};
-use utils::id::gen_id;
+use utils::auth::{Admin, AdminKeys, ApiKeys, Authenticated};
+use utils::expiry::{to_rfc3339, ExpiryConfig};
+use utils::filename::{self, FileName};
+use utils::files::local::{LocalDownload, LocalStorage, TransferError};
+use utils::files::s3::{create_storage_client, S3Storage};
+use utils::files::{file_name, Storage, StorageConfig, StorageKind};
+use utils::gc::{self, GcConfig};
+use utils::id::{gen_id, CodeConfig, CodeGenerator};
 use utils::log::setup_logger;
-use utils::rate_limit::RateLimitConfig;
+use utils::maintenance::{JobStatus, JobsConfig, Maintenance};
+use utils::password::{hash_password, verify_password, PasswordHeader, PASSWORD_ATTEMPTS};
+use utils::rate_limit::store::{MemoryStore, PostgresStore, RateLimitStore};
+use utils::rate_limit::{RateLimitAlgorithm, RateLimitBackend, RateLimitConfig, RateLimitHeaders};
+use utils::uploads::{ClientUploadLimits, UploadConfig};
+use utils::vanity::check_vanity_code;
 
 use dotenv::dotenv;
 
+use std::collections::HashMap;
 use std::env;
 use std::result::Result;
 use std::result::Result::Ok;
 use std::string::String;
+use std::sync::Arc;
 use std::time::Duration;
 
+use rocket::data::Data;
 use rocket::form::Form;
 use rocket::serde::json::Json;
 
-use utils::db::{self, collect_garbage};
+use models::{Clip, ClipType, MultipartUpload};
+use utils::db::{
+    self, ClipContent, ClipOptions, DbConfig, DbConn, DbPool, InsertClipError, PoolStatus,
+};
 
-use crate::utils::rate_limit::RateLimiter;
+use crate::utils::rate_limit::{ClientId, LimitReport, RateLimiter};
 use crate::utils::structs::{APIResponse, APIStatus};
 
-use aws_sdk_s3::Client;
-
 extern crate rand;
 extern crate serde;
 extern crate serde_json;
@@ -41,46 +56,325 @@ extern crate fern;
 extern crate log;
 
 include!(concat!(env!("OUT_DIR"), "/git_commit.rs"));
+
+/// Largest file that can be uploaded in a single request, in bytes
+/// Larger files have to be uploaded in parts
+const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024; // 100MB
+
 #[derive(rocket::FromForm, serde::Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct UploadQuery {
     name: String,
-    size: Option<usize>,
+    size: Option<u64>,
+    content_type: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    status: APIStatus,
+    /// The presigned URL to upload the file to
+    result: String,
+    /// The key to finish the upload with
+    object_key: String,
+    /// Headers that have to be sent with the upload exactly as given
+    headers: HashMap<String, String>,
+}
+
+/// Checks a file about to be uploaded against the upload limits
+/// Returns its sanitized name, its size and the content type it has to be uploaded with
+fn check_upload(
+    name: &str,
+    size: Option<u64>,
+    content_type: Option<&str>,
+    max_size: u64,
+) -> Result<(FileName, u64, String), Custom<Json<APIResponse>>> {
+    let name = match filename::sanitize(name) {
+        Some(name) => name,
+        None => {
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "File name is empty".to_string(),
+            };
+            return Err(Custom(Status::BadRequest, Json(response)));
+        }
+    };
+
+    // The size is signed into the upload URL, so it has to be known up front
+    let size = match size {
+        Some(size) if size > 0 => size,
+        _ => {
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "File size is required".to_string(),
+            };
+            return Err(Custom(Status::BadRequest, Json(response)));
+        }
+    };
+
+    if size > max_size {
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "File is too large".to_string(),
+        };
+        return Err(Custom(Status::PayloadTooLarge, Json(response)));
+    }
+
+    let content_type = match content_type {
+        Some(content_type) => match ContentType::parse_flexible(content_type) {
+            Some(content_type) => content_type.to_string(),
+            None => {
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: "Invalid content type".to_string(),
+                };
+                return Err(Custom(Status::BadRequest, Json(response)));
+            }
+        },
+        None => ContentType::Binary.to_string(),
+    };
+
+    Ok((name, size, content_type))
+}
+
+/// Generates the object key a file is uploaded under
+/// The random prefix keeps files with the same name apart
+fn upload_key(name: &FileName) -> String {
+    format!("{}/{}", gen_id(10), name.key)
 }
 
 #[get("/upload-file?<query..>")]
 async fn upload_file(
     _rate_limiter: RateLimiter,
-    s3_client: &State<Client>,
+    upload_limits: ClientUploadLimits,
+    storage: &State<Storage>,
     query: UploadQuery,
-) -> Result<Json<APIResponse>, Custom<Json<APIResponse>>> {
-    if query.name.is_empty() {
+) -> Result<Json<UploadResponse>, Custom<Json<APIResponse>>> {
+    let max_size = upload_limits.0.max_size.min(MAX_UPLOAD_SIZE);
+    let (name, size, content_type) = check_upload(
+        &query.name,
+        query.size,
+        query.content_type.as_deref(),
+        max_size,
+    )?;
+
+    let object_key = upload_key(&name);
+
+    match storage
+        .presign_upload(&object_key, size as i64, &content_type, &name.display)
+        .await
+    {
+        Ok(upload) => {
+            let response = UploadResponse {
+                status: APIStatus::Success,
+                result: upload.url,
+                object_key,
+                headers: upload.headers,
+            };
+            Ok(Json(response))
+        }
+        Err(err) => {
+            error!("{}", err);
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "A server-side problem has occurred".to_string(),
+            };
+            Err(Custom(Status::InternalServerError, Json(response)))
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct CompleteUploadRequest {
+    object_key: String,
+}
+
+fn invalid_object_key() -> Custom<Json<APIResponse>> {
+    let response = APIResponse {
+        status: APIStatus::Error,
+        result: "Invalid object key".to_string(),
+    };
+    Custom(Status::BadRequest, Json(response))
+}
+
+/// Looks up the clip an upload was already turned into
+/// Finishing the same upload twice gives back the same clip
+fn find_upload_clip(
+    db_connection: &mut DbConn,
+    object_key: &str,
+) -> Result<Option<Clip>, Custom<Json<APIResponse>>> {
+    db::get_clip_by_object_key(db_connection, object_key.to_string()).map_err(|e| {
+        error!("{}", e);
         let response = APIResponse {
             status: APIStatus::Error,
-            result: "File name is empty".to_string(),
+            result: "A problem with the database has occurred".to_string(),
         };
-        return Err(Custom(Status::BadRequest, Json(response)));
-    }
+        Custom(Status::InternalServerError, Json(response))
+    })
+}
 
-    let max_size = 100 * 1024 * 1024; // 100MB
-    if let Some(size) = query.size {
-        if size > max_size {
+/// Creates a file clip for an object that has been uploaded in full
+async fn create_file_clip(
+    object_key: &str,
+    max_size: u64,
+    storage: &Storage,
+    expiry_config: &ExpiryConfig,
+    codes: &CodeGenerator,
+    db_connection: &mut DbConn,
+) -> Result<Json<SetClipResponse>, Custom<Json<APIResponse>>> {
+    let object = match storage.head(object_key).await {
+        Ok(Some(object)) => object,
+        Ok(None) => {
             let response = APIResponse {
                 status: APIStatus::Error,
-                result: "File is too large".to_string(),
+                result: "Uploaded file not found".to_string(),
             };
-            return Err(Custom(Status::PayloadTooLarge, Json(response)));
+            return Err(Custom(Status::NotFound, Json(response)));
         }
+        Err(err) => {
+            error!("{}", err);
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "A server-side problem has occurred".to_string(),
+            };
+            return Err(Custom(Status::InternalServerError, Json(response)));
+        }
+    };
+
+    if object.size as u64 > max_size {
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "File is too large".to_string(),
+        };
+        return Err(Custom(Status::PayloadTooLarge, Json(response)));
     }
 
-    let bucket = "iclip";
-    let object_key = format!("{}/{}", gen_id(10), query.name);
+    let content = ClipContent::File {
+        object_key: object_key.to_string(),
+        size: object.size,
+        content_type: object.content_type,
+        // Objects uploaded before display names were kept only have the name in their key
+        file_name: object
+            .display_name
+            .unwrap_or_else(|| file_name(object_key).to_string()),
+    };
+    let options = ClipOptions {
+        expires_at: Some(expiry_config.default_expiry()),
+        max_views: None,
+        password_hash: None,
+    };
 
-    match put_object(s3_client, bucket, &object_key, 60).await {
-        Ok(presigned_url) => {
+    match db::insert_clip(db_connection, codes, content, &options) {
+        Ok(clip) => Ok(Json(clip.into())),
+        Err(e) => {
+            error!("{}", e);
             let response = APIResponse {
+                status: APIStatus::Error,
+                result: "A problem with the database has occurred".to_string(),
+            };
+            Err(Custom(Status::InternalServerError, Json(response)))
+        }
+    }
+}
+
+#[post("/upload/complete", data = "<form_data>")]
+async fn complete_upload(
+    form_data: Form<CompleteUploadRequest>,
+    _rate_limiter: RateLimiter,
+    upload_limits: ClientUploadLimits,
+    storage: &State<Storage>,
+    expiry_config: &State<ExpiryConfig>,
+    codes: &State<CodeGenerator>,
+    mut db_connection: DbConn,
+) -> Result<Json<SetClipResponse>, Custom<Json<APIResponse>>> {
+    let object_key = &form_data.object_key;
+    if !filename::is_upload_key(object_key) {
+        return Err(invalid_object_key());
+    }
+
+    if let Some(existing_clip) = find_upload_clip(&mut db_connection, object_key)? {
+        return Ok(Json(existing_clip.into()));
+    }
+
+    let max_size = upload_limits.0.max_size.min(MAX_UPLOAD_SIZE);
+    create_file_clip(
+        object_key,
+        max_size,
+        storage,
+        expiry_config,
+        codes,
+        &mut db_connection,
+    )
+    .await
+}
+
+#[derive(FromForm)]
+struct MultipartUploadRequest {
+    name: String,
+    size: Option<u64>,
+    content_type: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MultipartUploadResponse {
+    status: APIStatus,
+    /// The ID to upload the parts under
+    result: String,
+    /// The key to upload the parts and finish the upload with
+    object_key: String,
+    /// Size of every part except the last, in bytes
+    part_size: u64,
+    part_count: u64,
+}
+
+#[post("/upload/multipart", data = "<form_data>")]
+async fn initiate_multipart_upload(
+    form_data: Form<MultipartUploadRequest>,
+    _rate_limiter: RateLimiter,
+    upload_limits: ClientUploadLimits,
+    upload_config: &State<UploadConfig>,
+    storage: &State<Storage>,
+    mut db_connection: DbConn,
+) -> Result<Json<MultipartUploadResponse>, Custom<Json<APIResponse>>> {
+    let max_size = upload_limits.0.max_size;
+    let (name, size, content_type) = check_upload(
+        &form_data.name,
+        form_data.size,
+        form_data.content_type.as_deref(),
+        max_size,
+    )?;
+
+    let object_key = upload_key(&name);
+    let part_size = upload_config.part_size_for(size);
+
+    match storage
+        .create_multipart_upload(&object_key, &content_type, &name.display)
+        .await
+    {
+        Ok(upload_id) => {
+            // Parts are held to the declared size, so an upload can't grow past the tier's limit
+            let upload = MultipartUpload {
+                upload_id: upload_id.clone(),
+                object_key: object_key.clone(),
+                size: size as i64,
+                part_size: part_size as i64,
+                created_at: chrono::Local::now().naive_local(),
+            };
+            if let Err(e) = db::insert_multipart_upload(&mut db_connection, &upload) {
+                if let Err(err) = storage
+                    .abort_multipart_upload(&object_key, &upload_id)
+                    .await
+                {
+                    error!("{}", err);
+                }
+                return Err(database_error(e));
+            }
+
+            let response = MultipartUploadResponse {
                 status: APIStatus::Success,
-                result: presigned_url,
+                result: upload_id,
+                object_key,
+                part_size,
+                part_count: size.div_ceil(part_size),
             };
             Ok(Json(response))
         }
@@ -95,23 +389,259 @@ async fn upload_file(
     }
 }
 
-#[get("/status")]
-fn status(_rate_limiter: RateLimiter) -> Result<Json<APIResponse>, Custom<Json<APIResponse>>> {
-    let mut db_connection = match db::initialize() {
-        Ok(conn) => conn,
+#[derive(rocket::FromForm, serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UploadPartQuery {
+    object_key: String,
+    upload_id: String,
+    part_number: i32,
+    size: Option<u64>,
+}
+
+#[get("/upload/multipart/part?<query..>")]
+async fn presign_upload_part(
+    _rate_limiter: RateLimiter,
+    storage: &State<Storage>,
+    query: UploadPartQuery,
+    mut db_connection: DbConn,
+) -> Result<Json<UploadResponse>, Custom<Json<APIResponse>>> {
+    if !filename::is_upload_key(&query.object_key) {
+        return Err(invalid_object_key());
+    }
+
+    let upload = db::get_multipart_upload(&mut db_connection, &query.object_key, &query.upload_id)
+        .map_err(database_error)?
+        .ok_or_else(upload_not_found)?;
+
+    let (declared_size, part_size) = (upload.size as u64, upload.part_size as u64);
+    let part_count = declared_size.div_ceil(part_size);
+    if query.part_number < 1 || query.part_number as u64 > part_count {
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: format!("Part numbers must be between 1 and {}", part_count),
+        };
+        return Err(Custom(Status::BadRequest, Json(response)));
+    }
+
+    let size = match query.size {
+        Some(size) if size > 0 => size,
+        _ => {
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "Part size is required".to_string(),
+            };
+            return Err(Custom(Status::BadRequest, Json(response)));
+        }
+    };
+
+    // Only the last part may be smaller, and it only gets what is left of the declared size
+    let max_size = if query.part_number as u64 == part_count {
+        declared_size - (part_count - 1) * part_size
+    } else {
+        part_size
+    };
+    if size > max_size {
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "Part is too large".to_string(),
+        };
+        return Err(Custom(Status::PayloadTooLarge, Json(response)));
+    }
+
+    match storage
+        .presign_part(
+            &query.object_key,
+            &query.upload_id,
+            query.part_number,
+            size as i64,
+        )
+        .await
+    {
+        Ok(upload) => {
+            let response = UploadResponse {
+                status: APIStatus::Success,
+                result: upload.url,
+                object_key: query.object_key,
+                headers: upload.headers,
+            };
+            Ok(Json(response))
+        }
         Err(err) => {
             error!("{}", err);
             let response = APIResponse {
                 status: APIStatus::Error,
-                result: "A problem with the database has occurred".to_string(),
+                result: "A server-side problem has occurred".to_string(),
             };
-            return Err(Custom(Status::InternalServerError, Json(response)));
+            Err(Custom(Status::InternalServerError, Json(response)))
         }
+    }
+}
+
+#[derive(FromForm)]
+struct MultipartRequest {
+    object_key: String,
+    upload_id: String,
+}
+
+fn database_error(err: diesel::result::Error) -> Custom<Json<APIResponse>> {
+    error!("{}", err);
+    let response = APIResponse {
+        status: APIStatus::Error,
+        result: "A problem with the database has occurred".to_string(),
     };
+    Custom(Status::InternalServerError, Json(response))
+}
 
+fn upload_not_found() -> Custom<Json<APIResponse>> {
+    let response = APIResponse {
+        status: APIStatus::Error,
+        result: "Upload not found".to_string(),
+    };
+    Custom(Status::NotFound, Json(response))
+}
+
+/// Drops the record of a finished multipart upload
+/// Failures are only logged, as the garbage collector removes stale records as well
+fn forget_multipart_upload(db_connection: &mut DbConn, upload_id: &str) {
+    if let Err(e) = db::delete_multipart_upload(db_connection, upload_id) {
+        error!("{}", e);
+    }
+}
+
+#[post("/upload/multipart/complete", data = "<form_data>")]
+async fn complete_multipart(
+    form_data: Form<MultipartRequest>,
+    _rate_limiter: RateLimiter,
+    upload_limits: ClientUploadLimits,
+    storage: &State<Storage>,
+    expiry_config: &State<ExpiryConfig>,
+    codes: &State<CodeGenerator>,
+    mut db_connection: DbConn,
+) -> Result<Json<SetClipResponse>, Custom<Json<APIResponse>>> {
+    let object_key = &form_data.object_key;
+    let upload_id = &form_data.upload_id;
+    if !filename::is_upload_key(object_key) {
+        return Err(invalid_object_key());
+    }
+
+    if let Some(existing_clip) = find_upload_clip(&mut db_connection, object_key)? {
+        return Ok(Json(existing_clip.into()));
+    }
+
+    let server_error = |err: String| {
+        error!("{}", err);
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "A server-side problem has occurred".to_string(),
+        };
+        Custom(Status::InternalServerError, Json(response))
+    };
+
+    let parts = match storage.list_parts(object_key, upload_id).await {
+        Ok(Some(parts)) => parts,
+        Ok(None) => return Err(upload_not_found()),
+        Err(err) => return Err(server_error(err)),
+    };
+
+    if parts.is_empty() {
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "No parts have been uploaded".to_string(),
+        };
+        return Err(Custom(Status::BadRequest, Json(response)));
+    }
+
+    let max_size = upload_limits.0.max_size;
+    let size: u64 = parts.iter().map(|part| part.size as u64).sum();
+    if size > max_size {
+        // The parts can't be turned into a clip, so there's no point in keeping them around
+        if let Err(err) = storage.abort_multipart_upload(object_key, upload_id).await {
+            error!("{}", err);
+        }
+        forget_multipart_upload(&mut db_connection, upload_id);
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "File is too large".to_string(),
+        };
+        return Err(Custom(Status::PayloadTooLarge, Json(response)));
+    }
+
+    storage
+        .complete_multipart_upload(object_key, upload_id, &parts)
+        .await
+        .map_err(server_error)?;
+    forget_multipart_upload(&mut db_connection, upload_id);
+
+    create_file_clip(
+        object_key,
+        max_size,
+        storage,
+        expiry_config,
+        codes,
+        &mut db_connection,
+    )
+    .await
+}
+
+#[post("/upload/multipart/abort", data = "<form_data>")]
+async fn abort_multipart(
+    form_data: Form<MultipartRequest>,
+    _rate_limiter: RateLimiter,
+    storage: &State<Storage>,
+    mut db_connection: DbConn,
+) -> Result<Json<APIResponse>, Custom<Json<APIResponse>>> {
+    if !filename::is_upload_key(&form_data.object_key) {
+        return Err(invalid_object_key());
+    }
+
+    match storage
+        .abort_multipart_upload(&form_data.object_key, &form_data.upload_id)
+        .await
+    {
+        Ok(true) => {
+            forget_multipart_upload(&mut db_connection, &form_data.upload_id);
+            let response = APIResponse {
+                status: APIStatus::Success,
+                result: "Upload aborted".to_string(),
+            };
+            Ok(Json(response))
+        }
+        Ok(false) => Err(upload_not_found()),
+        Err(err) => {
+            error!("{}", err);
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "A server-side problem has occurred".to_string(),
+            };
+            Err(Custom(Status::InternalServerError, Json(response)))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    status: APIStatus,
+    result: String,
+    database_pool: PoolStatus,
+}
+
+#[get("/status")]
+fn status(
+    _rate_limiter: RateLimiter,
+    db_pool: &State<DbPool>,
+    expiry_config: &State<ExpiryConfig>,
+    codes: &State<CodeGenerator>,
+    mut db_connection: DbConn,
+) -> Result<Json<StatusResponse>, Custom<Json<APIResponse>>> {
     let url = "https://github.com".to_string();
 
-    let insert_result = db::insert_clip(&mut db_connection, url);
+    let options = ClipOptions {
+        expires_at: Some(expiry_config.default_expiry()),
+        max_views: None,
+        password_hash: None,
+    };
+
+    let insert_result = db::insert_clip(&mut db_connection, codes, ClipContent::Url(url), &options);
     if let Err(e) = insert_result {
         error!("{}", e);
         let response = APIResponse {
@@ -125,9 +655,10 @@ fn status(_rate_limiter: RateLimiter) -> Result<Json<APIResponse>, Custom<Json<A
 
     match result {
         Ok(_) => {
-            let response = APIResponse {
+            let response = StatusResponse {
                 status: APIStatus::Success,
                 result: "OK".to_string(),
+                database_pool: db::pool_status(db_pool),
             };
 
             Ok(Json(response))
@@ -143,6 +674,14 @@ fn status(_rate_limiter: RateLimiter) -> Result<Json<APIResponse>, Custom<Json<A
     }
 }
 
+#[catch(401)]
+fn unauthorized() -> Json<APIResponse> {
+    Json(APIResponse {
+        status: APIStatus::Error,
+        result: "Unauthorized".to_string(),
+    })
+}
+
 #[catch(429)]
 fn too_many_requests() -> Json<APIResponse> {
     Json(APIResponse {
@@ -151,6 +690,14 @@ fn too_many_requests() -> Json<APIResponse> {
     })
 }
 
+#[catch(503)]
+fn service_unavailable() -> Json<APIResponse> {
+    Json(APIResponse {
+        status: APIStatus::Error,
+        result: "A problem with the database has occurred".to_string(),
+    })
+}
+
 #[catch(404)]
 fn not_found() -> Json<APIResponse> {
     Json(APIResponse {
@@ -159,17 +706,77 @@ fn not_found() -> Json<APIResponse> {
     })
 }
 
+/// Largest text snippet a clip can hold, in bytes
+const MAX_TEXT_SIZE: usize = 16 * 1024;
+
 #[derive(FromForm)]
 struct SetClipRequest {
-    url: String,
+    url: Option<String>,
+    /// Text snippet to clip instead of a URL
+    text: Option<String>,
+    /// Seconds until the clip expires, or `never`
+    expires_in: Option<String>,
+    /// RFC 3339 timestamp at which the clip expires
+    expires_at: Option<String>,
+    /// Number of views after which the clip becomes unavailable
+    max_views: Option<i32>,
+    /// Password required to view the clip
+    password: Option<String>,
+    /// Code to create the clip under instead of a generated one
+    code: Option<String>,
 }
 
-#[post("/clip", data = "<form_data>")]
-fn set_clip(
-    form_data: Form<SetClipRequest>,
-    _rate_limiter: RateLimiter,
-) -> Result<Json<APIResponse>, Custom<Json<APIResponse>>> {
-    let url = &form_data.url;
+#[derive(Serialize)]
+struct SetClipResponse {
+    status: APIStatus,
+    result: String,
+    expires_at: Option<String>,
+    max_views: Option<i32>,
+}
+
+impl From<Clip> for SetClipResponse {
+    fn from(clip: Clip) -> Self {
+        SetClipResponse {
+            status: APIStatus::Success,
+            result: clip.code,
+            expires_at: clip.expires_at.map(to_rfc3339),
+            max_views: clip.max_views,
+        }
+    }
+}
+
+/// Validates what a new clip holds, either a URL or a text snippet
+fn clip_content(form_data: &SetClipRequest) -> Result<ClipContent, Custom<Json<APIResponse>>> {
+    let url = match (&form_data.url, &form_data.text) {
+        (Some(_), Some(_)) => {
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "Only one of url and text may be provided".to_string(),
+            };
+            return Err(Custom(Status::BadRequest, Json(response)));
+        }
+        (None, Some(text)) => {
+            if text.is_empty() {
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: "No text provided".to_string(),
+                };
+                return Err(Custom(Status::BadRequest, Json(response)));
+            }
+
+            if text.len() > MAX_TEXT_SIZE {
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: format!("Text must not be longer than {} bytes", MAX_TEXT_SIZE),
+                };
+                return Err(Custom(Status::PayloadTooLarge, Json(response)));
+            }
+
+            return Ok(ClipContent::Text(text.clone()));
+        }
+        (url, None) => url.as_deref().unwrap_or_default(),
+    };
+
     if url.is_empty() {
         let response = APIResponse {
             status: APIStatus::Error,
@@ -198,46 +805,137 @@ fn set_clip(
         return Err(Custom(Status::BadRequest, Json(response)));
     }
 
-    let mut db_connection = match db::initialize() {
-        Ok(conn) => conn,
-        Err(err) => {
-            error!("{}", err);
+    Ok(ClipContent::Url(url.to_string()))
+}
+
+#[post("/clip", data = "<form_data>")]
+async fn set_clip(
+    form_data: Form<SetClipRequest>,
+    _rate_limiter: RateLimiter,
+    authenticated: Option<Authenticated>,
+    expiry_config: &State<ExpiryConfig>,
+    codes: &State<CodeGenerator>,
+    mut db_connection: DbConn,
+) -> Result<Json<SetClipResponse>, Custom<Json<APIResponse>>> {
+    let content = clip_content(&form_data)?;
+
+    let expires_in = form_data.expires_in.as_deref();
+    let expires_at = form_data.expires_at.as_deref();
+    let expiry = match expiry_config.resolve(expires_in, expires_at, authenticated.is_some()) {
+        Ok(expiry) => expiry,
+        Err(e) => {
             let response = APIResponse {
                 status: APIStatus::Error,
-                result: "A problem with the database has occurred".to_string(),
+                result: e.to_string(),
             };
-            return Err(Custom(Status::InternalServerError, Json(response)));
+            return Err(Custom(e.status(), Json(response)));
         }
     };
 
-    // Check for existence of the URL in the database
-    let existing_clip = db::get_clip_by_url(&mut db_connection, url.to_string());
-
-    if let Ok(Some(existing_clip)) = existing_clip {
+    let max_views = form_data.max_views;
+    if max_views.is_some_and(|max_views| max_views < 1) {
         let response = APIResponse {
-            status: APIStatus::Success,
-            result: existing_clip.code,
+            status: APIStatus::Error,
+            result: "The view limit must be at least 1".to_string(),
         };
-        return Ok(Json(response));
+        return Err(Custom(Status::BadRequest, Json(response)));
     }
 
-    if let Err(e) = existing_clip {
-        error!("{}", e);
-        let response = APIResponse {
-            status: APIStatus::Error,
-            result: "A problem with the database has occurred".to_string(),
-        };
-        return Err(Custom(Status::InternalServerError, Json(response)));
+    let password_hash = match form_data.password.as_deref() {
+        None => None,
+        Some("") => {
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "The password must not be empty".to_string(),
+            };
+            return Err(Custom(Status::BadRequest, Json(response)));
+        }
+        Some(password) => match hash_password(password.to_string()).await {
+            Ok(password_hash) => Some(password_hash),
+            Err(e) => {
+                error!("{}", e);
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: "A server-side problem has occurred".to_string(),
+                };
+                return Err(Custom(Status::InternalServerError, Json(response)));
+            }
+        },
+    };
+
+    let vanity_code = match form_data.code.as_deref() {
+        None => None,
+        Some(requested) => {
+            let code = codes.normalize(requested);
+            if !codes.is_valid(&code) {
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: "Invalid clip code format".to_string(),
+                };
+                return Err(Custom(Status::BadRequest, Json(response)));
+            }
+
+            if let Err(e) = check_vanity_code(requested, |code| codes.normalize(code)) {
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: e.to_string(),
+                };
+                return Err(Custom(Status::BadRequest, Json(response)));
+            }
+
+            Some(code)
+        }
+    };
+
+    // Reuse an existing clip for the URL, unless the creator asked for specific options
+    let reusable_url = match &content {
+        ClipContent::Url(url)
+            if expires_in.is_none()
+                && expires_at.is_none()
+                && max_views.is_none()
+                && password_hash.is_none()
+                && vanity_code.is_none() =>
+        {
+            Some(url.clone())
+        }
+        _ => None,
+    };
+
+    if let Some(url) = reusable_url {
+        let existing_clip = db::get_clip_by_url(&mut db_connection, url);
+
+        if let Ok(Some(existing_clip)) = existing_clip {
+            return Ok(Json(existing_clip.into()));
+        }
+
+        if let Err(e) = existing_clip {
+            error!("{}", e);
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "A problem with the database has occurred".to_string(),
+            };
+            return Err(Custom(Status::InternalServerError, Json(response)));
+        }
     }
 
-    let result = db::insert_clip(&mut db_connection, url.to_string());
+    let options = ClipOptions {
+        expires_at: expiry,
+        max_views,
+        password_hash,
+    };
+
+    let result = match vanity_code {
+        Some(code) => db::insert_clip_with_code(&mut db_connection, codes, code, content, &options),
+        None => db::insert_clip(&mut db_connection, codes, content, &options),
+    };
     match result {
-        Ok(_) => {
+        Ok(clip) => Ok(Json(clip.into())),
+        Err(InsertClipError::CodeTaken) => {
             let response = APIResponse {
-                status: APIStatus::Success,
-                result: result.unwrap().code,
+                status: APIStatus::Error,
+                result: "This code is already taken".to_string(),
             };
-            Ok(Json(response))
+            Err(Custom(Status::Conflict, Json(response)))
         }
         Err(e) => {
             error!("{}", e);
@@ -250,11 +948,26 @@ fn set_clip(
     }
 }
 
-#[get("/clip?<code>")]
-fn get_clip(
+#[derive(Serialize)]
+struct GetClipResponse {
+    status: APIStatus,
+    /// The URL, the text of text clips or a download URL for file clips
+    result: String,
+    #[serde(rename = "type")]
+    clip_type: ClipType,
+}
+
+/// Looks up a clip and checks its password, counting the view if it may be seen
+/// Clips that are not of `required_type`, if given, are treated as missing
+async fn open_clip(
     code: String,
-    _rate_limiter: RateLimiter,
-) -> Result<Custom<Json<APIResponse>>, Custom<Json<APIResponse>>> {
+    password: Option<String>,
+    required_type: Option<ClipType>,
+    rate_limiter: &RateLimiter,
+    limit_report: &LimitReport<'_>,
+    codes: &CodeGenerator,
+    db_connection: &mut DbConn,
+) -> Result<Clip, Custom<Json<APIResponse>>> {
     if code.is_empty() {
         let response = APIResponse {
             status: APIStatus::Error,
@@ -263,8 +976,8 @@ fn get_clip(
         return Err(Custom(Status::BadRequest, Json(response)));
     }
 
-    let code_pattern = Regex::new(r"^(?i)[A-Z0-9]{5}$").unwrap();
-    if !code_pattern.is_match(code.as_str()) {
+    let code = codes.normalize(&code);
+    if !codes.is_valid(&code) {
         let response = APIResponse {
             status: APIStatus::Error,
             result: "Invalid clip code format".to_string(),
@@ -272,10 +985,17 @@ fn get_clip(
         return Err(Custom(Status::BadRequest, Json(response)));
     }
 
-    let mut db_connection = match db::initialize() {
-        Ok(conn) => conn,
-        Err(err) => {
-            error!("{}", err);
+    let clip = match db::find_clip(db_connection, code.clone()) {
+        Ok(Some(clip)) if required_type.is_none_or(|t| t == clip.clip_type) => clip,
+        Ok(_) => {
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "Clip not found".to_string(),
+            };
+            return Err(Custom(Status::NotFound, Json(response)));
+        }
+        Err(e) => {
+            error!("{}", e);
             let response = APIResponse {
                 status: APIStatus::Error,
                 result: "A problem with the database has occurred".to_string(),
@@ -284,15 +1004,46 @@ fn get_clip(
         }
     };
 
-    let result = db::get_clip(&mut db_connection, code);
-    match result {
-        Ok(Some(clip)) => {
+    if let Some(password_hash) = &clip.password_hash {
+        // Attempts are counted per clip, so guessing from many addresses does not help
+        let attempts_key = format!("clip password {}", clip.code);
+        if let Some(decision) = rate_limiter
+            .check_limited(&ClientId::Everyone, &attempts_key, &PASSWORD_ATTEMPTS)
+            .await
+        {
+            limit_report.report(decision);
             let response = APIResponse {
-                status: APIStatus::Success,
-                result: clip.url,
+                status: APIStatus::Error,
+                result: "Too many wrong password attempts, try again later".to_string(),
             };
-            Ok(Custom(Status::Ok, Json(response)))
+            return Err(Custom(Status::TooManyRequests, Json(response)));
         }
+
+        let password = match password {
+            Some(password) => password,
+            None => {
+                let response = APIResponse {
+                    status: APIStatus::Error,
+                    result: "This clip is password protected".to_string(),
+                };
+                return Err(Custom(Status::Unauthorized, Json(response)));
+            }
+        };
+
+        if !verify_password(password, password_hash.clone()).await {
+            rate_limiter
+                .record_attempt(&ClientId::Everyone, &attempts_key, &PASSWORD_ATTEMPTS)
+                .await;
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "Incorrect password".to_string(),
+            };
+            return Err(Custom(Status::Unauthorized, Json(response)));
+        }
+    }
+
+    match db::get_clip(db_connection, code) {
+        Ok(Some(clip)) => Ok(clip),
         Ok(None) => {
             let response = APIResponse {
                 status: APIStatus::Error,
@@ -311,6 +1062,139 @@ fn get_clip(
     }
 }
 
+/// Presigns a short-lived download link for a file clip
+async fn download_url(storage: &Storage, clip: &Clip) -> Result<String, Custom<Json<APIResponse>>> {
+    let object_key = &clip.url;
+    let name = clip
+        .file_name
+        .as_deref()
+        .unwrap_or_else(|| file_name(object_key));
+    match storage.presign_download(object_key, name).await {
+        Ok(presigned_url) => Ok(presigned_url),
+        Err(err) => {
+            error!("{}", err);
+            let response = APIResponse {
+                status: APIStatus::Error,
+                result: "A server-side problem has occurred".to_string(),
+            };
+            Err(Custom(Status::InternalServerError, Json(response)))
+        }
+    }
+}
+
+#[get("/clip?<code>&<password>")]
+#[allow(clippy::too_many_arguments)]
+async fn get_clip(
+    code: String,
+    password: Option<String>,
+    password_header: PasswordHeader,
+    rate_limiter: RateLimiter,
+    limit_report: LimitReport<'_>,
+    codes: &State<CodeGenerator>,
+    storage: &State<Storage>,
+    mut db_connection: DbConn,
+) -> Result<Custom<Json<GetClipResponse>>, Custom<Json<APIResponse>>> {
+    let password = password.or(password_header.0);
+    let clip = open_clip(
+        code,
+        password,
+        None,
+        &rate_limiter,
+        &limit_report,
+        codes,
+        &mut db_connection,
+    )
+    .await?;
+
+    // Files are handed out through short-lived links rather than their object key
+    let result = match clip.clip_type {
+        ClipType::File => download_url(storage, &clip).await?,
+        ClipType::Url | ClipType::Text => clip.url,
+    };
+
+    let response = GetClipResponse {
+        status: APIStatus::Success,
+        result,
+        clip_type: clip.clip_type,
+    };
+    Ok(Custom(Status::Ok, Json(response)))
+}
+
+/// Redirects to a download of a file clip, so its link can be shared directly
+#[get("/file/<code>?<password>")]
+#[allow(clippy::too_many_arguments)]
+async fn get_file(
+    code: String,
+    password: Option<String>,
+    password_header: PasswordHeader,
+    rate_limiter: RateLimiter,
+    limit_report: LimitReport<'_>,
+    codes: &State<CodeGenerator>,
+    storage: &State<Storage>,
+    mut db_connection: DbConn,
+) -> Result<Redirect, Custom<Json<APIResponse>>> {
+    let password = password.or(password_header.0);
+    let clip = open_clip(
+        code,
+        password,
+        Some(ClipType::File),
+        &rate_limiter,
+        &limit_report,
+        codes,
+        &mut db_connection,
+    )
+    .await?;
+
+    let presigned_url = download_url(storage, &clip).await?;
+    Ok(Redirect::temporary(presigned_url))
+}
+
+fn transfer_error(err: TransferError) -> Custom<Json<APIResponse>> {
+    let result = match &err {
+        TransferError::Io(_) => {
+            error!("{}", err);
+            "A server-side problem has occurred".to_string()
+        }
+        _ => err.to_string(),
+    };
+    let response = APIResponse {
+        status: APIStatus::Error,
+        result,
+    };
+    Custom(err.status(), Json(response))
+}
+
+/// Receives an upload to local storage through a URL handed out in place of a presigned S3 URL
+#[put("/storage/<token>", data = "<data>")]
+async fn storage_upload(
+    token: &str,
+    content_type: Option<&ContentType>,
+    data: Data<'_>,
+    storage: &State<Arc<LocalStorage>>,
+) -> Result<Json<APIResponse>, Custom<Json<APIResponse>>> {
+    let action = storage.verify(token).map_err(transfer_error)?;
+    storage
+        .receive(action, content_type, data)
+        .await
+        .map_err(transfer_error)?;
+
+    let response = APIResponse {
+        status: APIStatus::Success,
+        result: "Uploaded".to_string(),
+    };
+    Ok(Json(response))
+}
+
+/// Serves a file from local storage through a URL handed out in place of a presigned S3 URL
+#[get("/storage/<token>")]
+async fn storage_download(
+    token: &str,
+    storage: &State<Arc<LocalStorage>>,
+) -> Result<LocalDownload, Custom<Json<APIResponse>>> {
+    let action = storage.verify(token).map_err(transfer_error)?;
+    storage.send(action).await.map_err(transfer_error)
+}
+
 #[get("/clip")]
 fn get_clip_empty() -> Result<Custom<Json<APIResponse>>, Custom<Json<APIResponse>>> {
     Err(Custom(
@@ -330,19 +1214,8 @@ struct StatsResponse {
 #[get("/stats")]
 fn get_service_stats(
     _rate_limiter: RateLimiter,
+    mut db_connection: DbConn,
 ) -> Result<Custom<Json<StatsResponse>>, Custom<Json<APIResponse>>> {
-    let mut db_connection = match db::initialize() {
-        Ok(conn) => conn,
-        Err(err) => {
-            error!("{}", err);
-            let response = APIResponse {
-                status: APIStatus::Error,
-                result: "A problem with the database has occurred".to_string(),
-            };
-            return Err(Custom(Status::InternalServerError, Json(response)));
-        }
-    };
-
     let stats = db::get_total_clip_count(&mut db_connection);
     match stats {
         Ok(stats) => {
@@ -362,6 +1235,48 @@ fn get_service_stats(
     }
 }
 
+#[derive(Serialize)]
+struct JobsResponse {
+    status: APIStatus,
+    result: Vec<JobStatus>,
+}
+
+/// Lists the maintenance jobs along with how their last runs went
+#[get("/admin/jobs")]
+fn get_jobs(
+    _rate_limiter: RateLimiter,
+    _admin: Admin,
+    maintenance: &State<Maintenance>,
+) -> Json<JobsResponse> {
+    Json(JobsResponse {
+        status: APIStatus::Success,
+        result: maintenance.statuses(),
+    })
+}
+
+/// Runs a maintenance job now instead of waiting for its next scheduled run
+#[post("/admin/jobs/<name>/run")]
+fn run_job(
+    name: &str,
+    _rate_limiter: RateLimiter,
+    _admin: Admin,
+    maintenance: &State<Maintenance>,
+) -> Custom<Json<APIResponse>> {
+    if !maintenance.trigger(name) {
+        let response = APIResponse {
+            status: APIStatus::Error,
+            result: "Job not found".to_string(),
+        };
+        return Custom(Status::NotFound, Json(response));
+    }
+
+    let response = APIResponse {
+        status: APIStatus::Success,
+        result: "Job queued".to_string(),
+    };
+    Custom(Status::Accepted, Json(response))
+}
+
 #[derive(serde::Serialize)]
 struct Version {
     commit: Option<String>,
@@ -374,6 +1289,16 @@ fn version(_rate_limiter: RateLimiter) -> Json<Version> {
     })
 }
 
+/// Reads a section of `Rocket.toml`, falling back to its defaults only if the section is missing
+/// A section that is there but can't be read stops the server rather than being ignored
+fn read_config<T: DeserializeOwned + Default>(section: &str) -> T {
+    match rocket::Config::figment().extract_inner(section) {
+        Ok(config) => config,
+        Err(e) if e.missing() => T::default(),
+        Err(e) => panic!("Invalid {} configuration: {}", section, e),
+    }
+}
+
 #[launch]
 async fn rocket() -> _ {
     dotenv().ok();
@@ -387,17 +1312,64 @@ async fn rocket() -> _ {
         }
     };
 
-    let rate_limiter = RateLimiter::new();
+    let db_config: DbConfig = read_config("database");
+    let db_pool = db::create_pool(&db_config).expect("Failed to connect to the database");
+
+    let expiry_config: ExpiryConfig = read_config("expiry");
+    if let Err(e) = expiry_config.validate() {
+        panic!("Invalid expiry configuration: {}", e);
+    }
+
+    let code_config: CodeConfig = read_config("codes");
+    if let Err(e) = code_config.validate() {
+        panic!("Invalid code configuration: {}", e);
+    }
+
+    let codes = CodeGenerator::new(code_config);
+    let mut connection = db_pool.get().expect("Failed to connect to the database");
+    codes
+        .resume(|length| db::count_codes_of_length(&mut connection, length))
+        .expect("Failed to count clip codes");
+    drop(connection);
+
+    let upload_config: UploadConfig = read_config("uploads");
+    if let Err(e) = upload_config.validate() {
+        panic!("Invalid upload configuration: {}", e);
+    }
+
+    let rate_limit_backend: RateLimitBackend = read_config("rate_limit.store");
+    let rate_limit_store: Arc<dyn RateLimitStore> = match rate_limit_backend {
+        RateLimitBackend::Memory => Arc::new(MemoryStore::new()),
+        RateLimitBackend::Postgres => Arc::new(PostgresStore::new(db_pool.clone())),
+    };
+
+    let rate_limiter = RateLimiter::new(rate_limit_store);
+    rate_limiter
+        .add_config(
+            "POST /api/clip",
+            RateLimitConfig::new(Duration::from_secs(30), 50)
+                .with_algorithm(RateLimitAlgorithm::TokenBucket { burst: 10 }),
+        )
+        .await;
     rate_limiter
         .add_config(
             "/api/clip",
             RateLimitConfig::new(Duration::from_secs(30), 50),
         )
         .await;
+    // Large files are uploaded in hundreds of parts, each needing its own URL
+    rate_limiter
+        .add_config(
+            "/api/upload/multipart/part",
+            RateLimitConfig::new(Duration::from_secs(60), 600)
+                .with_algorithm(RateLimitAlgorithm::TokenBucket { burst: 100 }),
+        )
+        .await;
     rate_limiter
         .add_config(
             "/api/status",
-            RateLimitConfig::new(Duration::from_secs(30), 20),
+            RateLimitConfig::new(Duration::from_secs(30), 20)
+                .with_algorithm(RateLimitAlgorithm::SlidingLog),
         )
         .await;
     rate_limiter
@@ -407,34 +1379,152 @@ async fn rocket() -> _ {
         )
         .await;
 
-    let s3_client = create_storage_client().await.unwrap();
+    let storage_config: StorageConfig = read_config("storage");
+    if let Err(e) = storage_config.validate() {
+        panic!("Invalid storage configuration: {}", e);
+    }
+    let mut local_storage = None;
+    let storage: Storage = match storage_config.backend {
+        StorageKind::S3 => {
+            let client = create_storage_client(&storage_config.s3)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to set up storage: {}", e));
+            Arc::new(S3Storage::new(
+                client,
+                &storage_config.s3,
+                storage_config.presign_expiry.clone(),
+            ))
+        }
+        StorageKind::Local => {
+            let local =
+                LocalStorage::new(&storage_config.local, storage_config.presign_expiry.clone())
+                    .unwrap_or_else(|e| panic!("Failed to set up storage: {}", e));
+            let local = Arc::new(local);
+            local_storage = Some(local.clone());
+            local
+        }
+    };
+
+    let gc_config: GcConfig = read_config("gc");
+    if let Err(e) = gc_config.validate() {
+        panic!("Invalid garbage collection configuration: {}", e);
+    }
+    if gc_config.dry_run {
+        warn!("Garbage collection runs in dry-run mode, nothing is removed from storage");
+    }
+
+    let jobs_config: JobsConfig = read_config("jobs");
+    if let Err(e) = jobs_config.validate() {
+        panic!("Invalid jobs configuration: {}", e);
+    }
 
-    let mut db_connection = db::initialize().expect("Failed to connect to the database");
-    let mut scheduler = Scheduler::with_tz(chrono::Utc);
-    scheduler.every(1.hour()).run(move || {
-        let _ = collect_garbage(&mut db_connection);
-    });
+    let mut maintenance = Maintenance::new();
+    let (expiry_pool, expiry_storage, expiry_gc_config) =
+        (db_pool.clone(), storage.clone(), gc_config.clone());
+    // Replicas sharing the database take turns, so deletions don't race each other
+    maintenance.add_exclusive_job(
+        "expired-clips",
+        Duration::from_secs(jobs_config.expired_clips),
+        move || {
+            gc::delete_expired_clips(
+                expiry_pool.clone(),
+                expiry_storage.clone(),
+                expiry_gc_config.clone(),
+            )
+        },
+    );
+    let (sweep_pool, sweep_storage, sweep_gc_config) =
+        (db_pool.clone(), storage.clone(), gc_config.clone());
+    maintenance.add_exclusive_job(
+        "orphan-sweep",
+        Duration::from_secs(jobs_config.orphan_sweep),
+        move || {
+            gc::sweep_orphans(
+                sweep_pool.clone(),
+                sweep_storage.clone(),
+                sweep_gc_config.clone(),
+            )
+        },
+    );
+    let purge_pool = db_pool.clone();
+    let code_cooldown = codes.cooldown();
+    maintenance.add_exclusive_job(
+        "tombstone-purge",
+        Duration::from_secs(jobs_config.tombstone_purge),
+        move || gc::purge_tombstones(purge_pool.clone(), gc_config.clone(), code_cooldown),
+    );
+    let health_pool = db_pool.clone();
+    maintenance.add_job(
+        "pool-health",
+        Duration::from_secs(jobs_config.pool_health),
+        move || db::check_pool_health(health_pool.clone()),
+    );
 
-    rocket::build()
+    // Client IPs come from the peer address unless a trusted proxy header is opted into,
+    // as any client could otherwise pick its own IP and with it a fresh rate limit
+    let ip_header: Option<String> = read_config("rate_limit.ip_header");
+    let figment = match ip_header {
+        Some(header) => rocket::Config::figment().merge(("ip_header", header)),
+        None => rocket::Config::figment().merge(("ip_header", false)),
+    };
+
+    let rocket = rocket::custom(figment)
         .mount(
             "/api",
             routes![
                 status,
                 get_clip,
                 get_clip_empty,
+                get_file,
                 set_clip,
                 version,
                 get_service_stats,
-                upload_file
+                upload_file,
+                complete_upload,
+                initiate_multipart_upload,
+                presign_upload_part,
+                complete_multipart,
+                abort_multipart,
+                get_jobs,
+                run_job
+            ],
+        )
+        .register(
+            "/",
+            catchers![
+                unauthorized,
+                too_many_requests,
+                not_found,
+                service_unavailable
             ],
         )
-        .register("/", catchers![too_many_requests, not_found])
         .manage(rate_limiter)
+        .manage(ApiKeys::from_env())
+        .manage(AdminKeys::from_env())
+        .manage(db_pool)
+        .manage(expiry_config)
+        .manage(upload_config)
+        .manage(codes)
+        .attach(RateLimitHeaders)
+        .attach(maintenance.clone())
+        .manage(maintenance)
         .attach(rocket::fairing::AdHoc::on_response("Headers", |_, res| {
             Box::pin(async move {
                 // CORS headers
                 res.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+                res.set_header(Header::new(
+                    "Access-Control-Expose-Headers",
+                    "X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, Retry-After",
+                ));
             })
         }))
-        .manage(s3_client)
+        .manage(storage);
+
+    // Local storage stands in for S3 by serving transfers itself
+    match local_storage {
+        Some(local_storage) => rocket
+            .mount("/api", routes![storage_upload, storage_download])
+            .manage(local_storage),
+        None => rocket,
+    }
 }
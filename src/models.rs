@@ -1,16 +1,67 @@
 use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Text;
 use serde::{Deserialize, Serialize};
 
+use std::io::Write;
+
 use crate::schema::*;
 
+/// What a clip holds, stored in the `clip_type` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipType {
+    Url,
+    Text,
+    File,
+}
+
+impl ClipType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClipType::Url => "url",
+            ClipType::Text => "text",
+            ClipType::File => "file",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for ClipType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for ClipType {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"url" => Ok(ClipType::Url),
+            b"text" => Ok(ClipType::Text),
+            b"file" => Ok(ClipType::File),
+            other => Err(format!("Unknown clip type: {}", String::from_utf8_lossy(other)).into()),
+        }
+    }
+}
+
 #[derive(Insertable)]
 #[diesel(table_name = clips)]
 pub struct NewClip {
-    pub url: String,
+    pub url: String, // The URL, the text of text clips or the object key of file clips
     pub code: String,
     pub created_at: NaiveDateTime, // Include if not set by default in the database
     pub expires_at: Option<NaiveDateTime>, // Optional field
+    pub max_views: Option<i32>,
+    pub password_hash: Option<String>,
+    pub clip_type: ClipType,
+    pub file_size: Option<i64>,
+    pub content_type: Option<String>,
+    pub file_name: Option<String>, // The name the file was uploaded under, shown on download
 }
 
 #[derive(Debug, Queryable, Serialize, Deserialize)]
@@ -20,4 +71,33 @@ pub struct Clip {
     pub code: String,
     pub created_at: NaiveDateTime,
     pub expires_at: Option<NaiveDateTime>,
+    pub max_views: Option<i32>,
+    pub views: i32,
+    #[serde(skip_serializing)]
+    pub password_hash: Option<String>,
+    pub clip_type: ClipType,
+    pub file_size: Option<i64>,
+    pub content_type: Option<String>,
+    pub file_name: Option<String>,
+    /// When the clip expired, it is kept as a tombstone until it is purged
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+/// A multipart upload in progress, with the sizes it was started with
+#[derive(Insertable, Queryable)]
+#[diesel(table_name = multipart_uploads)]
+pub struct MultipartUpload {
+    pub upload_id: String,
+    pub object_key: String,
+    pub size: i64,
+    pub part_size: i64,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Queryable)]
+#[diesel(table_name = rate_limit_buckets)]
+pub struct RateLimitBucket {
+    pub key: String,
+    pub state: String,
+    pub expires_at: NaiveDateTime,
 }
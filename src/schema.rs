@@ -7,5 +7,72 @@ diesel::table! {
         code -> Text,
         created_at -> Timestamp,
         expires_at -> Nullable<Timestamp>,
+        max_views -> Nullable<Int4>,
+        views -> Int4,
+        password_hash -> Nullable<Text>,
+        clip_type -> Text,
+        file_size -> Nullable<Int8>,
+        content_type -> Nullable<Text>,
+        file_name -> Nullable<Text>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
+
+diesel::table! {
+    clips_archive (id) {
+        id -> Int4,
+        url -> Text,
+        code -> Text,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        max_views -> Nullable<Int4>,
+        views -> Int4,
+        clip_type -> Text,
+        file_size -> Nullable<Int8>,
+        content_type -> Nullable<Text>,
+        file_name -> Nullable<Text>,
+        deleted_at -> Timestamp,
+        archived_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    job_runs (name) {
+        name -> Text,
+        last_run -> Timestamp,
+    }
+}
+
+diesel::table! {
+    multipart_uploads (upload_id) {
+        upload_id -> Text,
+        object_key -> Text,
+        size -> Int8,
+        part_size -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    rate_limit_buckets (key) {
+        key -> Text,
+        state -> Text,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    retired_codes (code) {
+        code -> Text,
+        retired_at -> Timestamp,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    clips,
+    clips_archive,
+    job_runs,
+    multipart_uploads,
+    rate_limit_buckets,
+    retired_codes,
+);
@@ -1,6 +1,14 @@
+pub mod auth;
 pub(crate) mod db;
+pub mod expiry;
+pub mod filename;
 pub mod files;
+pub mod gc;
 pub(crate) mod id;
 pub mod log;
+pub mod maintenance;
+pub mod password;
 pub mod rate_limit;
 pub mod structs;
+pub mod uploads;
+pub mod vanity;
@@ -0,0 +1,98 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+
+use std::collections::HashSet;
+use std::env;
+
+/// The header clients use to present their API key
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// Reads a set of keys from a comma-separated environment variable
+fn keys_from_env(variable: &str) -> HashSet<String> {
+    env::var(variable)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The set of API keys accepted by the server
+pub struct ApiKeys(HashSet<String>);
+
+impl ApiKeys {
+    /// Reads the accepted keys from the comma-separated `API_KEYS` environment variable
+    pub fn from_env() -> Self {
+        ApiKeys(keys_from_env("API_KEYS"))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains(key)
+    }
+}
+
+/// The set of keys that give access to the admin endpoints
+/// Without any, the admin endpoints can't be used at all
+pub struct AdminKeys(HashSet<String>);
+
+impl AdminKeys {
+    /// Reads the keys from the comma-separated `ADMIN_KEYS` environment variable
+    pub fn from_env() -> Self {
+        AdminKeys(keys_from_env("ADMIN_KEYS"))
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains(key)
+    }
+}
+
+/// Returns the API key presented with the request, if it is one the server accepts
+pub fn api_key<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let keys = request.rocket().state::<ApiKeys>()?;
+
+    request
+        .headers()
+        .get_one(API_KEY_HEADER)
+        .filter(|key| keys.contains(key))
+}
+
+/// A request guard for clients that presented a valid API key
+pub struct Authenticated;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        match api_key(request) {
+            Some(_) => Outcome::Success(Authenticated),
+            None => Outcome::Forward(Status::Unauthorized),
+        }
+    }
+}
+
+/// A request guard for operators that presented an admin key in the API key header
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let is_admin = match (
+            request.rocket().state::<AdminKeys>(),
+            request.headers().get_one(API_KEY_HEADER),
+        ) {
+            (Some(keys), Some(key)) => keys.contains(key),
+            _ => false,
+        };
+
+        if is_admin {
+            Outcome::Success(Admin)
+        } else {
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
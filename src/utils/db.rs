@@ -1,51 +1,317 @@
 use crate::models::*;
 use crate::schema::*;
 
+use chrono::NaiveDateTime;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection};
 use diesel::result::DatabaseErrorKind;
 use diesel::result::Error;
+use diesel::sql_types::{Integer, Text};
+use diesel::upsert::excluded;
 
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Outcome};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
 use std::env;
 use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use super::id::CodeGenerator;
 
-use super::id::gen_id;
+pub type DbPool = Pool<ConnectionManager<PgConnection>>;
 
-/// Tries to connect to the database and if it doesn't exist, creates it from the current schema
-/// Returns the connection
-pub fn initialize() -> Result<PgConnection, ConnectionError> {
+/// Connection pool settings, read from the `database` section of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DbConfig {
+    /// Maximum number of connections held by the pool
+    pub pool_size: u32,
+    /// Number of idle connections the pool tries to keep around
+    pub min_idle: Option<u32>,
+    /// Seconds to wait for a free connection before giving up
+    pub connection_timeout: u64,
+    /// Seconds after which an unused connection is closed
+    pub idle_timeout: Option<u64>,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            pool_size: 10,
+            min_idle: None,
+            connection_timeout: 5,
+            idle_timeout: Some(300),
+        }
+    }
+}
+
+/// Creates the connection pool for the database at `DATABASE_URL`
+pub fn create_pool(config: &DbConfig) -> Result<DbPool, PoolError> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+
+    Pool::builder()
+        .max_size(config.pool_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(Duration::from_secs(config.connection_timeout))
+        .idle_timeout(config.idle_timeout.map(Duration::from_secs))
+        .build(manager)
 }
 
-/// Returns a clip from the database
-pub fn get_clip(
+/// A snapshot of the pool's health
+#[derive(Serialize)]
+pub struct PoolStatus {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+}
+
+/// Returns the current state of the connection pool
+pub fn pool_status(pool: &DbPool) -> PoolStatus {
+    let state = pool.state();
+    PoolStatus {
+        max_size: pool.max_size(),
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+    }
+}
+
+/// Runs `operation` on a pooled connection outside of the async runtime's worker threads
+pub async fn with_connection<T: Send + 'static>(
+    pool: &DbPool,
+    operation: impl FnOnce(&mut PgConnection) -> Result<T, Error> + Send + 'static,
+) -> Result<T, String> {
+    let pool = pool.clone();
+
+    rocket::tokio::task::spawn_blocking(move || {
+        let mut connection = pool
+            .get()
+            .map_err(|e| format!("Failed to get a database connection: {}", e))?;
+
+        operation(&mut connection).map_err(|e| format!("Database query failed: {}", e))
+    })
+    .await
+    .map_err(|e| format!("Database task failed: {}", e))?
+}
+
+/// Checks that a connection can be checked out and used, and reports how busy the pool is
+pub async fn check_pool_health(pool: DbPool) -> Result<String, String> {
+    with_connection(&pool, |connection| {
+        diesel::sql_query("SELECT 1").execute(connection)
+    })
+    .await?;
+
+    let status = pool_status(&pool);
+    if status.connections >= status.max_size && status.idle_connections == 0 {
+        warn!("All {} database connections are in use", status.max_size);
+    }
+
+    Ok(format!(
+        "{} of {} connections open, {} idle",
+        status.connections, status.max_size, status.idle_connections
+    ))
+}
+
+/// First key of the advisory locks taken for maintenance jobs, the second is the job's name hashed
+/// Keeps them apart from any other advisory locks taken on the same database
+const JOB_LOCK_NAMESPACE: i32 = 0x1c11;
+
+define_sql_function!(fn pg_try_advisory_lock(namespace: Integer, key: Integer) -> Bool);
+define_sql_function!(fn hashtext(text: Text) -> Integer);
+
+/// A Postgres advisory lock, held until this is dropped
+/// The lock lives on its own connection rather than a pooled one, so closing the connection is
+/// all it takes to release it, even if the job holding it is cancelled halfway through
+pub struct AdvisoryLock {
+    connection: PgConnection,
+}
+
+impl AdvisoryLock {
+    /// Records a run of the job called `name`, unless another one was recorded less than `gap` ago
+    /// Returns the lock back if the run should go ahead
+    /// Holding the lock keeps the check and the record from racing other instances
+    pub async fn claim_run(
+        mut self,
+        name: &'static str,
+        gap: Option<Duration>,
+    ) -> Result<Option<Self>, String> {
+        use diesel::dsl::{exists, now};
+        use diesel::pg::expression::extensions::IntervalDsl;
+
+        rocket::tokio::task::spawn_blocking(move || {
+            let connection = &mut self.connection;
+            if let Some(gap) = gap {
+                let since = now - gap.as_secs_f64().seconds();
+                let ran_recently = diesel::select(exists(
+                    job_runs::table
+                        .filter(job_runs::name.eq(name))
+                        .filter(job_runs::last_run.gt(since)),
+                ))
+                .get_result::<bool>(connection)
+                .map_err(|e| format!("Failed to look up the last run of {}: {}", name, e))?;
+                if ran_recently {
+                    return Ok(None);
+                }
+            }
+
+            diesel::insert_into(job_runs::table)
+                .values((job_runs::name.eq(name), job_runs::last_run.eq(now)))
+                .on_conflict(job_runs::name)
+                .do_update()
+                .set(job_runs::last_run.eq(excluded(job_runs::last_run)))
+                .execute(connection)
+                .map_err(|e| format!("Failed to record the run of {}: {}", name, e))?;
+
+            Ok(Some(self))
+        })
+        .await
+        .map_err(|e| format!("Database task failed: {}", e))?
+    }
+}
+
+/// Takes the advisory lock for the job called `name` on a new connection
+/// Returns `None` if another instance already holds it
+pub async fn try_job_lock(name: &'static str) -> Result<Option<AdvisoryLock>, String> {
+    rocket::tokio::task::spawn_blocking(move || {
+        let database_url = env::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set")?;
+        let mut connection = PgConnection::establish(&database_url)
+            .map_err(|e| format!("Failed to connect to the database: {}", e))?;
+
+        let locked = diesel::select(pg_try_advisory_lock(JOB_LOCK_NAMESPACE, hashtext(name)))
+            .get_result::<bool>(&mut connection)
+            .map_err(|e| format!("Failed to take the lock for {}: {}", name, e))?;
+
+        Ok(locked.then_some(AdvisoryLock { connection }))
+    })
+    .await
+    .map_err(|e| format!("Database task failed: {}", e))?
+}
+
+/// A pooled database connection, usable as a request guard
+pub struct DbConn(PooledConnection<ConnectionManager<PgConnection>>);
+
+impl Deref for DbConn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for DbConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbConn {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, ()> {
+        let pool = request
+            .rocket()
+            .state::<DbPool>()
+            .expect("DbPool registered as state")
+            .clone();
+
+        // Checking out a connection may block for up to `connection_timeout`
+        match rocket::tokio::task::spawn_blocking(move || pool.get()).await {
+            Ok(Ok(connection)) => Outcome::Success(DbConn(connection)),
+            Ok(Err(err)) => {
+                error!("{}", err);
+                Outcome::Error((Status::ServiceUnavailable, ()))
+            }
+            Err(err) => {
+                error!("{}", err);
+                Outcome::Error((Status::ServiceUnavailable, ()))
+            }
+        }
+    }
+}
+
+/// Returns a clip from the database without counting a view
+pub fn find_clip(
     connection: &mut PgConnection,
     clip_code: String,
 ) -> Result<Option<Clip>, diesel::result::Error> {
     use crate::schema::clips::dsl::*;
 
-    println!("Searching for clip code: {}", clip_code);
-
     clips
         .filter(code.eq(clip_code))
+        .filter(deleted_at.is_null())
         .filter(
             expires_at
                 .is_null()
                 .or(expires_at.gt(chrono::Local::now().naive_local())),
         )
+        .filter(max_views.is_null().or(views.nullable().lt(max_views)))
         .first::<Clip>(connection)
         .optional()
 }
 
+/// Returns a clip from the database, counting the view
+/// Clips which have reached their view limit are not returned
+pub fn get_clip(
+    connection: &mut PgConnection,
+    clip_code: String,
+) -> Result<Option<Clip>, diesel::result::Error> {
+    use crate::schema::clips::dsl::*;
+
+    println!("Searching for clip code: {}", clip_code);
+
+    // Checking the limit and counting the view in one statement keeps concurrent views from overshooting it
+    diesel::update(
+        clips
+            .filter(code.eq(clip_code))
+            .filter(deleted_at.is_null())
+            .filter(
+                expires_at
+                    .is_null()
+                    .or(expires_at.gt(chrono::Local::now().naive_local())),
+            )
+            .filter(max_views.is_null().or(views.nullable().lt(max_views))),
+    )
+    .set(views.eq(views + 1))
+    .get_result::<Clip>(connection)
+    .optional()
+}
+
 /// Looks for a clip in the database by its URL
-/// Returns the clip if it exists
+/// Returns the clip if it exists and has neither a view limit nor a password
 pub fn get_clip_by_url(
     connection: &mut PgConnection,
     url: String,
 ) -> Result<Option<Clip>, diesel::result::Error> {
     clips::table
+        .filter(clips::clip_type.eq(ClipType::Url))
         .filter(clips::url.eq(url))
+        .filter(clips::deleted_at.is_null())
+        .filter(clips::max_views.is_null())
+        .filter(clips::password_hash.is_null())
+        .filter(
+            clips::expires_at
+                .is_null()
+                .or(clips::expires_at.gt(chrono::Local::now().naive_local())),
+        )
+        .first::<Clip>(connection)
+        .optional()
+}
+
+/// Looks for the clip of an uploaded file by its object key
+/// Returns the clip if it exists
+pub fn get_clip_by_object_key(
+    connection: &mut PgConnection,
+    object_key: String,
+) -> Result<Option<Clip>, diesel::result::Error> {
+    clips::table
+        .filter(clips::clip_type.eq(ClipType::File))
+        .filter(clips::url.eq(object_key))
+        .filter(clips::deleted_at.is_null())
         .filter(
             clips::expires_at
                 .is_null()
@@ -59,6 +325,7 @@ pub fn get_clip_by_url(
 pub enum InsertClipError {
     DieselError(diesel::result::Error),
     MaxAttemptsExceeded,
+    CodeTaken,
 }
 impl From<diesel::result::Error> for InsertClipError {
     fn from(error: diesel::result::Error) -> Self {
@@ -72,37 +339,107 @@ impl fmt::Display for InsertClipError {
             InsertClipError::MaxAttemptsExceeded => {
                 write!(f, "Exceeded maximum attempts to generate a unique code.")
             }
+            InsertClipError::CodeTaken => write!(f, "The requested code is already taken."),
         }
     }
 }
 
-/// Inserts a clip into the database
+/// Settings chosen by a clip's creator
+pub struct ClipOptions {
+    /// When the clip expires, `None` meaning never
+    pub expires_at: Option<NaiveDateTime>,
+    /// Number of views after which the clip becomes unavailable
+    pub max_views: Option<i32>,
+    /// Produced by `password::hash_password`
+    pub password_hash: Option<String>,
+}
+
+/// What a new clip holds
+#[derive(Clone)]
+pub enum ClipContent {
+    Url(String),
+    Text(String),
+    /// An uploaded object in the storage bucket
+    File {
+        object_key: String,
+        size: i64,
+        content_type: Option<String>,
+        /// The name the file was uploaded under
+        file_name: String,
+    },
+}
+
+fn new_clip(code: String, content: ClipContent, options: &ClipOptions) -> NewClip {
+    let (clip_type, url, file_size, content_type, file_name) = match content {
+        ClipContent::Url(url) => (ClipType::Url, url, None, None, None),
+        ClipContent::Text(text) => (ClipType::Text, text, None, None, None),
+        ClipContent::File {
+            object_key,
+            size,
+            content_type,
+            file_name,
+        } => (
+            ClipType::File,
+            object_key,
+            Some(size),
+            content_type,
+            Some(file_name),
+        ),
+    };
+
+    NewClip {
+        url,
+        code,
+        created_at: chrono::Local::now().naive_local(),
+        expires_at: options.expires_at,
+        max_views: options.max_views,
+        password_hash: options.password_hash.clone(),
+        clip_type,
+        file_size,
+        content_type,
+        file_name,
+    }
+}
+
+fn is_unique_violation(error: &InsertClipError) -> bool {
+    matches!(
+        error,
+        InsertClipError::DieselError(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _))
+    )
+}
+
+/// Inserts a clip holding `content` under a generated code
 /// Returns the inserted clip
-pub fn insert_clip(connection: &mut PgConnection, url: String) -> Result<Clip, InsertClipError> {
-    let expiry_date = chrono::Local::now().naive_local() + chrono::Duration::days(7);
-    let mut attempts = 0;
+pub fn insert_clip(
+    connection: &mut PgConnection,
+    codes: &CodeGenerator,
+    content: ClipContent,
+    options: &ClipOptions,
+) -> Result<Clip, InsertClipError> {
     const MAX_ATTEMPTS: usize = 10; // Maximum attempts to generate a unique code
 
-    while attempts < MAX_ATTEMPTS {
-        let code = gen_id(5);
-        let new_clip = NewClip {
-            url: url.clone(),
-            code: code.clone(),
-            created_at: chrono::Local::now().naive_local(),
-            expires_at: Some(expiry_date),
-        };
+    let cooldown_cutoff = cooldown_cutoff(codes);
+
+    for attempt in 0..MAX_ATTEMPTS {
+        let code = codes.generate(attempt);
+
+        // Codes of recently expired clips still circulate in old links, so they count as taken
+        if is_code_retired(connection, &code, cooldown_cutoff)? {
+            codes.record(true);
+            continue;
+        }
 
         match diesel::insert_into(clips::table)
-            .values(&new_clip)
+            .values(&new_clip(code, content.clone(), options))
             .get_result::<Clip>(connection)
             .map_err(InsertClipError::from)
         {
-            Ok(clip) => return Ok(clip),
-            Err(InsertClipError::DieselError(Error::DatabaseError(
-                DatabaseErrorKind::UniqueViolation,
-                _,
-            ))) => {
-                attempts += 1;
+            Ok(clip) => {
+                codes.record(false);
+                return Ok(clip);
+            }
+            Err(e) if is_unique_violation(&e) => {
+                codes.record(true);
                 continue;
             }
             Err(e) => return Err(e), // For any other diesel error, return immediately
@@ -112,6 +449,67 @@ pub fn insert_clip(connection: &mut PgConnection, url: String) -> Result<Clip, I
     Err(InsertClipError::MaxAttemptsExceeded)
 }
 
+/// Inserts a clip holding `content` under a code chosen by its creator
+/// Returns the inserted clip, or `CodeTaken` if another clip already uses the code
+pub fn insert_clip_with_code(
+    connection: &mut PgConnection,
+    codes: &CodeGenerator,
+    code: String,
+    content: ClipContent,
+    options: &ClipOptions,
+) -> Result<Clip, InsertClipError> {
+    if is_code_retired(connection, &code, cooldown_cutoff(codes))? {
+        return Err(InsertClipError::CodeTaken);
+    }
+
+    diesel::insert_into(clips::table)
+        .values(&new_clip(code, content, options))
+        .get_result::<Clip>(connection)
+        .map_err(|e| match InsertClipError::from(e) {
+            e if is_unique_violation(&e) => InsertClipError::CodeTaken,
+            e => e,
+        })
+}
+
+/// The time codes retired before are free to be handed out again
+fn cooldown_cutoff(codes: &CodeGenerator) -> NaiveDateTime {
+    chrono::Local::now().naive_local() - chrono::Duration::seconds(codes.cooldown() as i64)
+}
+
+/// Whether `code` belonged to a clip that was purged after `cutoff`
+fn is_code_retired(
+    connection: &mut PgConnection,
+    code: &str,
+    cutoff: NaiveDateTime,
+) -> Result<bool, diesel::result::Error> {
+    diesel::select(diesel::dsl::exists(
+        retired_codes::table
+            .filter(retired_codes::code.eq(code))
+            .filter(retired_codes::retired_at.gt(cutoff)),
+    ))
+    .get_result(connection)
+}
+
+define_sql_function!(fn char_length(text: Text) -> Integer);
+
+/// Counts the codes of `length` characters that can't be handed out,
+/// because a clip or a tombstone holds them or they are retired
+pub fn count_codes_of_length(
+    connection: &mut PgConnection,
+    length: usize,
+) -> Result<i64, diesel::result::Error> {
+    let length = length as i32;
+    let clips = clips::table
+        .filter(char_length(clips::code).eq(length))
+        .count()
+        .get_result::<i64>(connection)?;
+    let retired = retired_codes::table
+        .filter(char_length(retired_codes::code).eq(length))
+        .count()
+        .get_result::<i64>(connection)?;
+    Ok(clips + retired)
+}
+
 /// Returns the highest ID in the clips table
 pub fn get_total_clip_count(connection: &mut PgConnection) -> Result<i32, diesel::result::Error> {
     use crate::schema::clips::dsl::*;
@@ -123,16 +521,149 @@ pub fn get_total_clip_count(connection: &mut PgConnection) -> Result<i32, diesel
         .ok_or(diesel::result::Error::NotFound)
 }
 
-/// Deletes expired clips from the database
-pub fn collect_garbage(connection: &mut PgConnection) -> Result<usize, diesel::result::Error> {
+/// Marks expired clips and clips which have reached their view limit as deleted
+/// The rows are kept as tombstones until `purge_tombstones` removes them
+/// Returns the newly deleted clips, so the objects of file clips can be removed from storage
+pub fn tombstone_expired_clips(
+    connection: &mut PgConnection,
+) -> Result<Vec<Clip>, diesel::result::Error> {
     use crate::schema::clips::dsl::*;
 
-    diesel::delete(
-        clips.filter(
+    let now = chrono::Local::now().naive_local();
+    diesel::update(
+        clips.filter(deleted_at.is_null()).filter(
             expires_at
                 .is_not_null()
-                .and(expires_at.lt(chrono::Local::now().naive_local())),
+                .and(expires_at.lt(now))
+                .or(max_views.is_not_null().and(views.nullable().ge(max_views))),
         ),
     )
-    .execute(connection)
+    .set(deleted_at.eq(now))
+    .get_results(connection)
+}
+
+/// Removes the tombstones of clips deleted before `cutoff`, copying them to the archive first
+/// if `archive` is set
+/// Their codes are moved to `retired_codes`, which keeps them from being reused right away
+/// Returns the number of clips removed
+pub fn purge_tombstones(
+    connection: &mut PgConnection,
+    cutoff: NaiveDateTime,
+    archive: bool,
+) -> Result<usize, diesel::result::Error> {
+    connection.transaction(|connection| {
+        let purged = clips::table.filter(clips::deleted_at.lt(cutoff));
+
+        // A code can be retired again once its cooldown is over and a new clip got it
+        diesel::insert_into(retired_codes::table)
+            .values(purged.select((clips::code, clips::deleted_at.assume_not_null())))
+            .into_columns((retired_codes::code, retired_codes::retired_at))
+            .on_conflict(retired_codes::code)
+            .do_update()
+            .set(retired_codes::retired_at.eq(excluded(retired_codes::retired_at)))
+            .execute(connection)?;
+
+        if archive {
+            // Password hashes are of no use once the clip is gone, so they aren't archived
+            diesel::insert_into(clips_archive::table)
+                .values(purged.select((
+                    clips::id,
+                    clips::url,
+                    clips::code,
+                    clips::created_at,
+                    clips::expires_at,
+                    clips::max_views,
+                    clips::views,
+                    clips::clip_type,
+                    clips::file_size,
+                    clips::content_type,
+                    clips::file_name,
+                    clips::deleted_at.assume_not_null(),
+                )))
+                .into_columns((
+                    clips_archive::id,
+                    clips_archive::url,
+                    clips_archive::code,
+                    clips_archive::created_at,
+                    clips_archive::expires_at,
+                    clips_archive::max_views,
+                    clips_archive::views,
+                    clips_archive::clip_type,
+                    clips_archive::file_size,
+                    clips_archive::content_type,
+                    clips_archive::file_name,
+                    clips_archive::deleted_at,
+                ))
+                .execute(connection)?;
+        }
+
+        diesel::delete(purged).execute(connection)
+    })
+}
+
+/// Frees the codes retired before `cutoff`
+/// Returns the number of codes freed
+pub fn release_retired_codes(
+    connection: &mut PgConnection,
+    cutoff: NaiveDateTime,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(retired_codes::table.filter(retired_codes::retired_at.lt(cutoff)))
+        .execute(connection)
+}
+
+/// Returns which of `object_keys` belong to a file clip
+pub fn referenced_object_keys(
+    connection: &mut PgConnection,
+    object_keys: &[String],
+) -> Result<HashSet<String>, diesel::result::Error> {
+    let referenced = clips::table
+        .filter(clips::clip_type.eq(ClipType::File))
+        .filter(clips::url.eq_any(object_keys))
+        .filter(clips::deleted_at.is_null())
+        .select(clips::url)
+        .load::<String>(connection)?;
+
+    Ok(referenced.into_iter().collect())
+}
+
+/// Records a multipart upload that has been started, with its declared size and part size
+pub fn insert_multipart_upload(
+    connection: &mut PgConnection,
+    upload: &MultipartUpload,
+) -> Result<usize, diesel::result::Error> {
+    diesel::insert_into(multipart_uploads::table)
+        .values(upload)
+        .execute(connection)
+}
+
+/// Returns the multipart upload `upload_id` of `object_key`, if it is in progress
+pub fn get_multipart_upload(
+    connection: &mut PgConnection,
+    object_key: &str,
+    upload_id: &str,
+) -> Result<Option<MultipartUpload>, diesel::result::Error> {
+    multipart_uploads::table
+        .filter(multipart_uploads::upload_id.eq(upload_id))
+        .filter(multipart_uploads::object_key.eq(object_key))
+        .first::<MultipartUpload>(connection)
+        .optional()
+}
+
+/// Forgets a multipart upload once it has been completed or aborted
+pub fn delete_multipart_upload(
+    connection: &mut PgConnection,
+    upload_id: &str,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(multipart_uploads::table.filter(multipart_uploads::upload_id.eq(upload_id)))
+        .execute(connection)
+}
+
+/// Forgets the multipart uploads started before `cutoff`
+/// Returns the number of uploads forgotten
+pub fn delete_stale_multipart_uploads(
+    connection: &mut PgConnection,
+    cutoff: NaiveDateTime,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(multipart_uploads::table.filter(multipart_uploads::created_at.lt(cutoff)))
+        .execute(connection)
 }
@@ -0,0 +1,225 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use rocket::http::Status;
+use serde::Deserialize;
+
+use std::fmt;
+
+/// Longest lifetime that can be configured, so expiry times stay far from overflowing
+const MAX_EXPIRY: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Bounds for clip expiry, in seconds, read from the `expiry` section of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    /// Lifetime of clips created without an explicit expiry
+    pub default: u64,
+    /// Shortest lifetime a creator may choose
+    pub min: u64,
+    /// Longest lifetime a creator may choose
+    pub max: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            default: 7 * 24 * 60 * 60,
+            min: 60,
+            max: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExpiryError {
+    Conflicting,
+    Invalid,
+    NeverNotAllowed,
+    OutOfBounds { min: u64, max: u64 },
+}
+
+impl ExpiryError {
+    /// The HTTP status the error should be reported with
+    pub fn status(&self) -> Status {
+        match self {
+            ExpiryError::NeverNotAllowed => Status::Forbidden,
+            _ => Status::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for ExpiryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpiryError::Conflicting => {
+                write!(f, "Only one of expires_in and expires_at may be provided")
+            }
+            ExpiryError::Invalid => write!(f, "Invalid expiry"),
+            ExpiryError::NeverNotAllowed => {
+                write!(
+                    f,
+                    "Only authenticated clients can create clips that never expire"
+                )
+            }
+            ExpiryError::OutOfBounds { min, max } => {
+                write!(f, "Expiry must be between {} and {} seconds", min, max)
+            }
+        }
+    }
+}
+
+impl ExpiryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max > MAX_EXPIRY {
+            return Err(format!("max must be at most {} seconds", MAX_EXPIRY));
+        }
+        if self.min > self.max {
+            return Err("min must not be greater than max".to_string());
+        }
+        if self.default < self.min || self.default > self.max {
+            return Err("default must be between min and max".to_string());
+        }
+        Ok(())
+    }
+
+    fn out_of_bounds(&self) -> ExpiryError {
+        ExpiryError::OutOfBounds {
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    /// The expiry of a clip created now without an explicit one
+    pub fn default_expiry(&self) -> NaiveDateTime {
+        Local::now().naive_local() + chrono::Duration::seconds(self.default as i64)
+    }
+
+    /// Turns the expiry requested by a clip's creator into the time it expires at
+    ///
+    /// `expires_in` is a number of seconds or `never`, `expires_at` an RFC 3339 timestamp.
+    /// Returns `None` for clips that never expire.
+    pub fn resolve(
+        &self,
+        expires_in: Option<&str>,
+        expires_at: Option<&str>,
+        authenticated: bool,
+    ) -> Result<Option<NaiveDateTime>, ExpiryError> {
+        let now = Local::now().naive_local();
+
+        let expiry = match (expires_in, expires_at) {
+            (None, None) => return Ok(Some(self.default_expiry())),
+            (Some(_), Some(_)) => return Err(ExpiryError::Conflicting),
+            (Some("never"), None) => {
+                return if authenticated {
+                    Ok(None)
+                } else {
+                    Err(ExpiryError::NeverNotAllowed)
+                };
+            }
+            (Some(seconds), None) => {
+                let seconds = seconds.parse::<i64>().map_err(|_| ExpiryError::Invalid)?;
+                // Checked before building the duration, which would overflow for huge values
+                if seconds < self.min as i64 || seconds > self.max as i64 {
+                    return Err(self.out_of_bounds());
+                }
+                now + chrono::Duration::seconds(seconds)
+            }
+            (None, Some(timestamp)) => DateTime::parse_from_rfc3339(timestamp)
+                .map_err(|_| ExpiryError::Invalid)?
+                .with_timezone(&Local)
+                .naive_local(),
+        };
+
+        let lifetime = (expiry - now).num_seconds();
+        if lifetime < self.min as i64 || lifetime > self.max as i64 {
+            return Err(self.out_of_bounds());
+        }
+
+        Ok(Some(expiry))
+    }
+}
+
+/// Formats a timestamp stored in local time as RFC 3339, including the offset
+pub fn to_rfc3339(timestamp: NaiveDateTime) -> String {
+    match timestamp.and_local_timezone(Local).earliest() {
+        Some(timestamp) => timestamp.to_rfc3339(),
+        // Falls into a DST gap, so there is no offset to attach
+        None => timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(expires_in: &str) -> Result<Option<NaiveDateTime>, ExpiryError> {
+        ExpiryConfig::default().resolve(Some(expires_in), None, false)
+    }
+
+    #[test]
+    fn accepts_expiry_within_bounds() {
+        let expiry = resolve("3600").unwrap().unwrap();
+        let lifetime = (expiry - Local::now().naive_local()).num_seconds();
+        assert!((3598..=3600).contains(&lifetime));
+    }
+
+    #[test]
+    fn rejects_huge_expiry_without_overflowing() {
+        for seconds in [
+            "10000000000000",
+            "9223372036854775807",
+            "-9223372036854775808",
+        ] {
+            assert!(matches!(
+                resolve(seconds),
+                Err(ExpiryError::OutOfBounds { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_expiry() {
+        assert!(matches!(resolve("soon"), Err(ExpiryError::Invalid)));
+        assert!(matches!(
+            resolve("99999999999999999999"),
+            Err(ExpiryError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn only_authenticated_clients_can_skip_expiry() {
+        let config = ExpiryConfig::default();
+        assert!(matches!(
+            config.resolve(Some("never"), None, true),
+            Ok(None)
+        ));
+        assert!(matches!(
+            config.resolve(Some("never"), None, false),
+            Err(ExpiryError::NeverNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn validate_checks_bounds() {
+        assert!(ExpiryConfig::default().validate().is_ok());
+
+        let config = ExpiryConfig {
+            min: 100,
+            max: 10,
+            ..ExpiryConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = ExpiryConfig {
+            default: 5,
+            min: 10,
+            max: 100,
+        };
+        assert!(config.validate().is_err());
+
+        let config = ExpiryConfig {
+            max: u64::MAX,
+            ..ExpiryConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest name shown to users, in characters
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 255;
+/// Longest name used in object keys, in bytes
+pub const MAX_KEY_NAME_LENGTH: usize = 100;
+/// Longest suffix treated as an extension and kept when a name is shortened
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+/// An uploaded file's name, in the two forms it is kept in
+pub struct FileName {
+    /// What the user uploaded, cleaned up just enough to be shown and saved safely
+    pub display: String,
+    /// An ASCII-only version that is safe to use in object keys and paths
+    pub key: String,
+}
+
+/// Characters that don't render but can reorder or disguise the rest of a name,
+/// e.g. right-to-left overrides turning `exe.txt` into `txt.exe`
+fn is_invisible(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            '\u{00AD}'
+                | '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2064}'
+                | '\u{2066}'..='\u{2069}'
+                | '\u{FEFF}'
+        )
+}
+
+/// Splits a name into its stem and extension, including the dot
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(index)
+            if index > 0
+                && name.len() - index <= MAX_EXTENSION_LENGTH
+                && !name[index..].contains(' ') =>
+        {
+            name.split_at(index)
+        }
+        _ => (name, ""),
+    }
+}
+
+/// Shortens a name to at most `max_length` as measured by `length`, keeping its extension
+fn truncate(name: &str, max_length: usize, length: fn(&str) -> usize) -> String {
+    if length(name) <= max_length {
+        return name.to_string();
+    }
+
+    let (stem, extension) = split_extension(name);
+    let (stem, extension) = if length(extension) < max_length {
+        (stem, extension)
+    } else {
+        (name, "")
+    };
+
+    let mut shortened = String::new();
+    for c in stem.chars() {
+        let mut candidate = shortened.clone();
+        candidate.push(c);
+        if length(&candidate) + length(extension) > max_length {
+            break;
+        }
+        shortened = candidate;
+    }
+
+    let shortened = shortened.trim_end();
+    format!("{}{}", shortened, extension)
+}
+
+/// Cleans a name for display: strips invisible characters and path separators,
+/// collapses whitespace and leading or trailing dots
+fn display_name(name: &str) -> String {
+    let cleaned: String = name
+        .nfc()
+        .filter(|&c| !is_invisible(c))
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_whitespace() => ' ',
+            c => c,
+        })
+        .collect();
+    let collapsed = cleaned.split(' ').filter(|part| !part.is_empty());
+    let cleaned = collapsed.collect::<Vec<_>>().join(" ");
+    let cleaned = cleaned.trim_matches(|c| c == '.' || c == ' ');
+
+    truncate(cleaned, MAX_DISPLAY_NAME_LENGTH, |name| {
+        name.chars().count()
+    })
+}
+
+/// Whether a character may appear in the name part of an object key
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')
+}
+
+/// Turns a display name into an ASCII name, dropping accents and replacing everything else
+fn key_name(display: &str) -> String {
+    let mut name = String::new();
+    for c in display.nfkd() {
+        if is_combining_mark(c) {
+            continue;
+        }
+        let c = if is_key_char(c) { c } else { '_' };
+        // Runs of replaced characters become a single underscore
+        if c == '_' && name.ends_with('_') {
+            continue;
+        }
+        name.push(c);
+    }
+
+    let name = name.trim_matches(|c| c == '.' || c == '_');
+    let name = truncate(name, MAX_KEY_NAME_LENGTH, str::len);
+    if name.is_empty() {
+        "file".to_string()
+    } else {
+        name
+    }
+}
+
+/// Sanitizes the name a file is uploaded under
+/// Returns `None` if nothing is left of it
+pub fn sanitize(name: &str) -> Option<FileName> {
+    let display = display_name(name);
+    if display.is_empty() {
+        return None;
+    }
+
+    let key = key_name(&display);
+    Some(FileName { display, key })
+}
+
+/// Whether `name` could have been produced by `sanitize` as the name part of an object key
+pub fn is_key_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_KEY_NAME_LENGTH
+        && name.chars().all(is_key_char)
+        && !name.starts_with('.')
+}
+
+/// Whether `object_key` has the shape of the keys handed out by `upload_file`, `<id>/<name>`
+pub fn is_upload_key(object_key: &str) -> bool {
+    match object_key.split_once('/') {
+        Some((prefix, name)) => {
+            prefix.len() == 10
+                && prefix
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+                && is_key_name(name)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_path_traversal() {
+        let name = sanitize("../../etc/passwd").unwrap();
+        assert!(!name.display.contains('/'));
+        assert_eq!(name.key, "etc_passwd");
+        assert!(is_key_name(&name.key));
+
+        let name = sanitize("..\\..\\boot.ini").unwrap();
+        assert!(!name.display.contains('\\'));
+        assert_eq!(name.key, "boot.ini");
+
+        // Only separators and dots leave nothing usable for the key
+        let name = sanitize("../").unwrap();
+        assert_eq!(name.key, "file");
+    }
+
+    #[test]
+    fn rejects_names_with_nothing_left() {
+        for name in ["", "   ", "...", "\u{202E}", "\u{200B}\u{FEFF}", "\n\t"] {
+            assert!(sanitize(name).is_none(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn strips_invisible_characters() {
+        // Would be shown as `invoiceexe.pdf`
+        let name = sanitize("invoice\u{202E}fdp.exe").unwrap();
+        assert_eq!(name.display, "invoicefdp.exe");
+        assert_eq!(name.key, "invoicefdp.exe");
+
+        let name = sanitize("a\u{200B}b\nc\u{2066}.txt").unwrap();
+        assert_eq!(name.display, "abc.txt");
+    }
+
+    #[test]
+    fn collapses_whitespace() {
+        let name = sanitize("  my   holiday \u{00A0} photo .jpg ").unwrap();
+        assert_eq!(name.display, "my holiday photo .jpg");
+        assert_eq!(name.key, "my_holiday_photo_.jpg");
+    }
+
+    #[test]
+    fn transliterates_keys() {
+        let name = sanitize("Ünïcödé résumé.pdf").unwrap();
+        assert_eq!(name.display, "Ünïcödé résumé.pdf");
+        assert_eq!(name.key, "Unicode_resume.pdf");
+
+        let name = sanitize("日本語.txt").unwrap();
+        assert_eq!(name.display, "日本語.txt");
+        assert!(is_key_name(&name.key));
+    }
+
+    #[test]
+    fn truncates_long_names_keeping_the_extension() {
+        let name = sanitize(&format!("{}.pdf", "a".repeat(300))).unwrap();
+        assert_eq!(name.display.chars().count(), MAX_DISPLAY_NAME_LENGTH);
+        assert!(name.display.ends_with("a.pdf"));
+        assert_eq!(name.key.len(), MAX_KEY_NAME_LENGTH);
+        assert!(name.key.ends_with("a.pdf"));
+        assert!(is_key_name(&name.key));
+
+        // Characters are counted for display names and bytes for keys
+        let name = sanitize(&format!("{}.txt", "é".repeat(300))).unwrap();
+        assert_eq!(name.display.chars().count(), MAX_DISPLAY_NAME_LENGTH);
+        assert!(name.display.ends_with("é.txt"));
+        assert_eq!(name.key.len(), MAX_KEY_NAME_LENGTH);
+        assert!(name.key.ends_with("e.txt"));
+    }
+
+    #[test]
+    fn truncates_names_without_an_extension() {
+        let long = "b".repeat(300);
+        let name = sanitize(&long).unwrap();
+        assert_eq!(name.display, "b".repeat(MAX_DISPLAY_NAME_LENGTH));
+        assert_eq!(name.key, "b".repeat(MAX_KEY_NAME_LENGTH));
+
+        // Too long to be an extension, so it is cut like the rest of the name
+        let name = sanitize(&format!("archive.{}", "c".repeat(300))).unwrap();
+        assert_eq!(name.key.len(), MAX_KEY_NAME_LENGTH);
+        assert!(name.key.starts_with("archive.c"));
+    }
+
+    #[test]
+    fn key_names_are_checked() {
+        assert!(is_key_name("report.pdf"));
+        assert!(is_key_name("a-b_c.tar.gz"));
+        for name in ["", "../x", "a/b", ".hidden", "caf\u{e9}", "a b"] {
+            assert!(!is_key_name(name), "{:?}", name);
+        }
+        assert!(!is_key_name(&"a".repeat(MAX_KEY_NAME_LENGTH + 1)));
+    }
+}
@@ -1,56 +1,289 @@
-use std::time::Duration;
-
-use crate::env;
-
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::presigning::config::PresigningConfig;
-use aws_sdk_s3::Client;
-use aws_sdk_s3::{Endpoint, Region};
-
-pub async fn create_storage_client() -> Result<Client, aws_sdk_s3::Error> {
-    let shared_config = aws_config::from_env()
-        .region(RegionProviderChain::default_provider().or_else("eu-central-1"))
-        .load()
-        .await;
-
-    if let Ok(endpoint_str) = env::var("CUSTOM_ENDPOINT") {
-        let region = shared_config
-            .region()
-            .cloned()
-            .unwrap_or_else(|| Region::new("eu-central-1"));
-        let credentials_provider = shared_config.credentials_provider().unwrap().clone();
-        let endpoint = Endpoint::immutable(endpoint_str.parse().expect("Invalid URI"));
-
-        let client_config = aws_sdk_s3::Config::builder()
-            .region(region)
-            .endpoint_resolver(endpoint)
-            .credentials_provider(credentials_provider)
-            .build();
-
-        return Ok(Client::from_conf(client_config));
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use url::Url;
+
+pub mod local;
+pub mod s3;
+
+/// The storage backend shared by all routes
+pub type Storage = Arc<dyn StorageBackend>;
+
+/// Which backend keeps uploaded files, read from `storage.backend` in `Rocket.toml`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    S3,
+    /// Files are kept on this server's disk and transferred through its own routes
+    Local,
+}
+
+/// Seconds presigned URLs stay valid
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PresignExpiry {
+    pub upload: u64,
+    /// Parts of multipart uploads are larger, so their URLs are given more time
+    pub part_upload: u64,
+    pub download: u64,
+}
+
+impl Default for PresignExpiry {
+    fn default() -> Self {
+        PresignExpiry {
+            upload: 60,
+            part_upload: 15 * 60,
+            download: 5 * 60,
+        }
+    }
+}
+
+/// Settings of the S3 storage backend
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct S3StorageConfig {
+    pub bucket: String,
+    /// Prepended to every object key, so the bucket can be shared with other data
+    pub key_prefix: String,
+    /// Region used when none is configured in the environment
+    pub region: String,
+    /// Endpoint of an S3 compatible service such as MinIO, `CUSTOM_ENDPOINT` if not set
+    pub endpoint: Option<String>,
+}
+
+impl Default for S3StorageConfig {
+    fn default() -> Self {
+        S3StorageConfig {
+            bucket: "iclip".to_string(),
+            key_prefix: String::new(),
+            region: "eu-central-1".to_string(),
+            endpoint: None,
+        }
+    }
+}
+
+impl S3StorageConfig {
+    fn validate(&self) -> Result<(), String> {
+        let bucket_chars_valid = self
+            .bucket
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-');
+        let bucket_ends_valid = self.bucket.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && self.bucket.ends_with(|c: char| c.is_ascii_alphanumeric());
+        if !(3..=63).contains(&self.bucket.len()) || !bucket_chars_valid || !bucket_ends_valid {
+            return Err(format!(
+                "\"{}\" is not a valid bucket name: use 3 to 63 lowercase letters, digits, dots \
+                 and hyphens, starting and ending with a letter or digit",
+                self.bucket
+            ));
+        }
+        if self.key_prefix.starts_with('/') {
+            return Err("The key prefix must not start with a slash".to_string());
+        }
+        if self.region.is_empty() {
+            return Err("The fallback region must not be empty".to_string());
+        }
+        if let Some(endpoint) = &self.endpoint {
+            validate_url(endpoint).map_err(|e| format!("Invalid S3 endpoint: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the local storage backend
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LocalStorageConfig {
+    /// Directory files are kept in
+    pub path: PathBuf,
+    /// URL clients reach this server at, which upload and download links are built on
+    pub public_url: String,
+}
+
+impl Default for LocalStorageConfig {
+    fn default() -> Self {
+        LocalStorageConfig {
+            path: PathBuf::from("storage"),
+            public_url: "http://localhost:8000".to_string(),
+        }
+    }
+}
+
+/// Storage settings, read from the `storage` section of `Rocket.toml`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageKind,
+    pub presign_expiry: PresignExpiry,
+    pub s3: S3StorageConfig,
+    pub local: LocalStorageConfig,
+}
+
+/// Longest a presigned S3 URL can be valid for
+const MAX_PRESIGN_EXPIRY: u64 = 7 * 24 * 60 * 60;
+
+impl StorageConfig {
+    /// Checks the settings of the configured backend
+    pub fn validate(&self) -> Result<(), String> {
+        let expiries = [
+            ("upload", self.presign_expiry.upload),
+            ("part_upload", self.presign_expiry.part_upload),
+            ("download", self.presign_expiry.download),
+        ];
+        for (name, expiry) in expiries {
+            if !(1..=MAX_PRESIGN_EXPIRY).contains(&expiry) {
+                return Err(format!(
+                    "presign_expiry.{} must be between 1 and {} seconds",
+                    name, MAX_PRESIGN_EXPIRY
+                ));
+            }
+        }
+
+        match self.backend {
+            StorageKind::S3 => self.s3.validate(),
+            StorageKind::Local => validate_url(&self.local.public_url)
+                .map_err(|e| format!("Invalid public URL for local storage: {}", e)),
+        }
     }
+}
 
-    Ok(Client::new(&shared_config))
-}
-
-pub async fn put_object(
-    client: &Client,
-    bucket: &str,
-    object: &str,
-    expires_in: u64,
-) -> Result<String, String> {
-    let expires_in = Duration::from_secs(expires_in);
-    let presigning_config = PresigningConfig::expires_in(expires_in)
-        .map_err(|e| format!("Failed to create presigning config: {}", e))?;
-
-    match client
-        .put_object()
-        .bucket(bucket)
-        .key(object)
-        .presigned(presigning_config)
-        .await
-    {
-        Ok(presigned_request) => Ok(presigned_request.uri().to_string()),
-        Err(e) => Err(format!("Failed to create presigned URL: {}", e)),
+fn validate_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("unsupported scheme {}", scheme)),
     }
 }
+
+/// A presigned upload and the headers the client has to send along with it
+pub struct PresignedUpload {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+}
+
+/// Size, type and name of a stored object
+pub struct ObjectInfo {
+    pub size: i64,
+    pub content_type: Option<String>,
+    /// The name the file was uploaded under, before it was sanitized for the object key
+    pub display_name: Option<String>,
+}
+
+/// A part that has been uploaded to a multipart upload
+pub struct UploadedPart {
+    pub part_number: i32,
+    pub e_tag: String,
+    pub size: i64,
+}
+
+/// An object in storage, as found when listing it
+pub struct StoredObject {
+    pub key: String,
+    pub size: i64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// A multipart upload that hasn't been completed or aborted yet
+pub struct PendingUpload {
+    pub key: String,
+    pub upload_id: String,
+    pub initiated: Option<SystemTime>,
+}
+
+/// Keeps uploaded files
+/// Clients transfer files through presigned URLs, so file contents never pass through the API
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Presigns an upload of exactly `size` bytes of `content_type`
+    /// Both are part of the signature, so uploads that don't match them are rejected
+    /// `display_name` is kept with the object and returned by `head`
+    async fn presign_upload(
+        &self,
+        object: &str,
+        size: i64,
+        content_type: &str,
+        display_name: &str,
+    ) -> Result<PresignedUpload, String>;
+
+    /// Presigns a download that saves the object as `file_name`
+    async fn presign_download(&self, object: &str, file_name: &str) -> Result<String, String>;
+
+    /// Looks up an object's metadata
+    /// Returns `None` if the object doesn't exist
+    async fn head(&self, object: &str) -> Result<Option<ObjectInfo>, String>;
+
+    async fn delete(&self, object: &str) -> Result<(), String>;
+
+    /// Lists every stored object
+    async fn list(&self) -> Result<Vec<StoredObject>, String>;
+
+    /// Starts a multipart upload and returns its upload ID
+    async fn create_multipart_upload(
+        &self,
+        object: &str,
+        content_type: &str,
+        display_name: &str,
+    ) -> Result<String, String>;
+
+    /// Presigns the upload of one part of exactly `size` bytes
+    async fn presign_part(
+        &self,
+        object: &str,
+        upload_id: &str,
+        part_number: i32,
+        size: i64,
+    ) -> Result<PresignedUpload, String>;
+
+    /// Lists the parts uploaded so far, in order
+    /// Returns `None` if the upload doesn't exist, e.g. because it was completed or aborted
+    async fn list_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<Option<Vec<UploadedPart>>, String>;
+
+    /// Assembles the uploaded parts into the final object
+    async fn complete_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), String>;
+
+    /// Discards a multipart upload and the parts uploaded to it
+    /// Returns `false` if the upload doesn't exist
+    async fn abort_multipart_upload(&self, object: &str, upload_id: &str) -> Result<bool, String>;
+
+    /// Lists the multipart uploads in progress
+    async fn list_multipart_uploads(&self) -> Result<Vec<PendingUpload>, String>;
+}
+
+/// The name a file was uploaded under, taken from its object key
+pub fn file_name(object: &str) -> &str {
+    object.rsplit_once('/').map_or(object, |(_, name)| name)
+}
+
+/// Builds a `Content-Disposition` value that makes browsers save a download as `file_name`
+fn attachment_disposition(file_name: &str) -> String {
+    // Older clients only understand the plain ASCII `filename` parameter
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' ' => ' ',
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        utf8_percent_encode(file_name, NON_ALPHANUMERIC)
+    )
+}
@@ -0,0 +1,834 @@
+use std::env;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use blake2::digest::{Digest, Mac};
+use blake2::{Blake2s256, Blake2sMac256};
+use rocket::data::{Data, ToByteUnit};
+use rocket::fs::NamedFile;
+use rocket::http::{ContentType, Header, Status};
+use rocket::tokio::{fs, io};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    attachment_disposition, LocalStorageConfig, ObjectInfo, PendingUpload, PresignExpiry,
+    PresignedUpload, StorageBackend, StoredObject, UploadedPart,
+};
+use crate::utils::id::gen_id;
+
+/// Where the routes serving transfers of the local backend are mounted
+pub const TRANSFER_ROUTE: &str = "/api/storage";
+
+/// What a transfer token allows its bearer to do
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TransferAction {
+    Upload {
+        object: String,
+        size: u64,
+        content_type: String,
+        display_name: String,
+    },
+    UploadPart {
+        object: String,
+        upload_id: String,
+        part_number: i32,
+        size: u64,
+    },
+    Download {
+        object: String,
+        file_name: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Token {
+    #[serde(flatten)]
+    action: TransferAction,
+    /// Seconds since the Unix epoch after which the token is no longer accepted
+    expires: u64,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    InvalidToken,
+    ExpiredToken,
+    NotFound,
+    ContentTypeMismatch,
+    SizeMismatch,
+    Io(String),
+}
+
+impl TransferError {
+    /// The HTTP status the error should be reported with
+    pub fn status(&self) -> Status {
+        match self {
+            TransferError::InvalidToken | TransferError::ExpiredToken => Status::Forbidden,
+            TransferError::NotFound => Status::NotFound,
+            TransferError::ContentTypeMismatch | TransferError::SizeMismatch => Status::BadRequest,
+            TransferError::Io(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::InvalidToken => write!(f, "Invalid transfer token"),
+            TransferError::ExpiredToken => write!(f, "Transfer token has expired"),
+            TransferError::NotFound => write!(f, "File not found"),
+            TransferError::ContentTypeMismatch => {
+                write!(
+                    f,
+                    "Content type doesn't match the one the upload was signed for"
+                )
+            }
+            TransferError::SizeMismatch => {
+                write!(f, "Size doesn't match the one the upload was signed for")
+            }
+            TransferError::Io(err) => write!(f, "Failed to access storage: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for TransferError {
+    fn from(err: std::io::Error) -> Self {
+        TransferError::Io(err.to_string())
+    }
+}
+
+/// A stored file, sent as an attachment
+#[derive(Responder)]
+pub struct LocalDownload {
+    file: NamedFile,
+    content_type: ContentType,
+    disposition: Header<'static>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ObjectMetadata {
+    content_type: String,
+    /// Missing for objects stored before display names were kept
+    #[serde(default)]
+    display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UploadMetadata {
+    object: String,
+    content_type: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    /// Seconds since the Unix epoch
+    initiated: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Turns an object key into a path relative to the storage directory
+/// Rejects keys that would point outside of it
+fn relative_path(key: &str) -> Result<PathBuf, String> {
+    let path = Path::new(key);
+    let is_safe = path.components().count() > 0
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+    if is_safe {
+        Ok(path.to_path_buf())
+    } else {
+        Err(format!("Invalid object key: {}", key))
+    }
+}
+
+fn is_upload_id(upload_id: &str) -> bool {
+    !upload_id.is_empty() && upload_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Keeps files in a directory on this server
+/// Transfers go through this server's own routes, authorized by signed tokens in place of
+/// presigned S3 URLs
+pub struct LocalStorage {
+    root: PathBuf,
+    public_url: String,
+    mac: Blake2sMac256,
+    expiry: PresignExpiry,
+}
+
+impl LocalStorage {
+    /// Sets up the storage directory, signing tokens with the `STORAGE_SECRET` environment variable
+    pub fn new(config: &LocalStorageConfig, expiry: PresignExpiry) -> Result<Self, String> {
+        let secret = match env::var("STORAGE_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                warn!("STORAGE_SECRET is not set, transfer links stop working on restart");
+                rand::random::<[u8; 32]>().to_vec()
+            }
+        };
+        Self::with_secret(config, expiry, &secret)
+    }
+
+    /// Sets up the storage directory, signing tokens with `secret`
+    pub fn with_secret(
+        config: &LocalStorageConfig,
+        expiry: PresignExpiry,
+        secret: &[u8],
+    ) -> Result<Self, String> {
+        for dir in ["objects", "meta", "uploads", "tmp"] {
+            std::fs::create_dir_all(config.path.join(dir))
+                .map_err(|e| format!("Failed to create storage directory: {}", e))?;
+        }
+
+        let mac = Blake2sMac256::new_from_slice(&Blake2s256::digest(secret))
+            .map_err(|e| format!("Invalid storage secret: {}", e))?;
+
+        Ok(LocalStorage {
+            root: config.path.clone(),
+            public_url: config.public_url.trim_end_matches('/').to_string(),
+            mac,
+            expiry,
+        })
+    }
+
+    fn sign(&self, action: TransferAction, expires_in: u64) -> Result<String, String> {
+        self.sign_token(&Token {
+            action,
+            expires: now() + expires_in,
+        })
+    }
+
+    fn sign_token(&self, token: &Token) -> Result<String, String> {
+        let payload =
+            serde_json::to_vec(token).map_err(|e| format!("Failed to create token: {}", e))?;
+
+        let mut mac = self.mac.clone();
+        mac.update(&payload);
+        let signature = mac.finalize().into_bytes();
+
+        Ok(format!(
+            "{}{}/{}.{}",
+            self.public_url,
+            TRANSFER_ROUTE,
+            Base64UrlUnpadded::encode_string(&payload),
+            Base64UrlUnpadded::encode_string(&signature)
+        ))
+    }
+
+    /// Checks a transfer token and returns what it allows
+    pub fn verify(&self, token: &str) -> Result<TransferAction, TransferError> {
+        let (payload, signature) = token.split_once('.').ok_or(TransferError::InvalidToken)?;
+        let payload =
+            Base64UrlUnpadded::decode_vec(payload).map_err(|_| TransferError::InvalidToken)?;
+        let signature =
+            Base64UrlUnpadded::decode_vec(signature).map_err(|_| TransferError::InvalidToken)?;
+
+        let mut mac = self.mac.clone();
+        mac.update(&payload);
+        mac.verify_slice(&signature)
+            .map_err(|_| TransferError::InvalidToken)?;
+
+        let token: Token =
+            serde_json::from_slice(&payload).map_err(|_| TransferError::InvalidToken)?;
+        if token.expires < now() {
+            return Err(TransferError::ExpiredToken);
+        }
+
+        Ok(token.action)
+    }
+
+    fn object_path(&self, object: &str) -> Result<PathBuf, String> {
+        Ok(self.root.join("objects").join(relative_path(object)?))
+    }
+
+    fn metadata_path(&self, object: &str) -> Result<PathBuf, String> {
+        let mut path = self.root.join("meta").join(relative_path(object)?);
+        path.as_mut_os_string().push(".json");
+        Ok(path)
+    }
+
+    fn upload_path(&self, upload_id: &str) -> Result<PathBuf, String> {
+        if !is_upload_id(upload_id) {
+            return Err(format!("Invalid upload ID: {}", upload_id));
+        }
+        Ok(self.root.join("uploads").join(upload_id))
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.root.join("tmp").join(gen_id(16))
+    }
+
+    async fn write_metadata<T: Serialize>(&self, path: &Path, metadata: &T) -> Result<(), String> {
+        let contents = serde_json::to_vec(metadata)
+            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+        fs::write(path, contents)
+            .await
+            .map_err(|e| format!("Failed to write metadata: {}", e))
+    }
+
+    async fn read_metadata<T: for<'de> Deserialize<'de>>(
+        &self,
+        path: &Path,
+    ) -> Result<Option<T>, String> {
+        match fs::read(path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| format!("Invalid metadata in {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to read metadata: {}", e)),
+        }
+    }
+
+    /// The metadata of a multipart upload of `object`
+    /// Returns `None` if no such upload is in progress
+    async fn pending_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<Option<UploadMetadata>, String> {
+        let path = self.upload_path(upload_id)?.join("upload.json");
+        let metadata: Option<UploadMetadata> = self.read_metadata(&path).await?;
+        Ok(metadata.filter(|metadata| metadata.object == object))
+    }
+
+    /// Writes a request body of exactly `size` bytes to `destination`
+    async fn receive_file(
+        &self,
+        data: Data<'_>,
+        size: u64,
+        destination: &Path,
+    ) -> Result<(), TransferError> {
+        let temp_path = self.temp_path();
+        let file = match data.open(size.bytes()).into_file(&temp_path).await {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(e.into());
+            }
+        };
+
+        // Bodies longer than `size` are cut off, which leaves the file incomplete
+        if !file.is_complete() || file.n.written != size {
+            fs::remove_file(&temp_path).await?;
+            return Err(TransferError::SizeMismatch);
+        }
+
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&temp_path, destination).await?;
+        Ok(())
+    }
+
+    /// Stores the body of an upload authorized by `action`
+    pub async fn receive(
+        &self,
+        action: TransferAction,
+        content_type: Option<&ContentType>,
+        data: Data<'_>,
+    ) -> Result<(), TransferError> {
+        match action {
+            TransferAction::Upload {
+                object,
+                size,
+                content_type: expected_content_type,
+                display_name,
+            } => {
+                if content_type.map(ContentType::to_string) != Some(expected_content_type.clone()) {
+                    return Err(TransferError::ContentTypeMismatch);
+                }
+
+                let path = self.object_path(&object).map_err(TransferError::Io)?;
+                self.receive_file(data, size, &path).await?;
+
+                let metadata = ObjectMetadata {
+                    content_type: expected_content_type,
+                    display_name: Some(display_name),
+                };
+                let metadata_path = self.metadata_path(&object).map_err(TransferError::Io)?;
+                self.write_metadata(&metadata_path, &metadata)
+                    .await
+                    .map_err(TransferError::Io)
+            }
+            TransferAction::UploadPart {
+                object,
+                upload_id,
+                part_number,
+                size,
+            } => {
+                if self
+                    .pending_upload(&object, &upload_id)
+                    .await
+                    .map_err(TransferError::Io)?
+                    .is_none()
+                {
+                    return Err(TransferError::NotFound);
+                }
+
+                let path = self
+                    .upload_path(&upload_id)
+                    .map_err(TransferError::Io)?
+                    .join(format!("part-{}", part_number));
+                self.receive_file(data, size, &path).await
+            }
+            TransferAction::Download { .. } => Err(TransferError::InvalidToken),
+        }
+    }
+
+    /// Opens the file a download token was issued for
+    pub async fn send(&self, action: TransferAction) -> Result<LocalDownload, TransferError> {
+        let (object, file_name) = match action {
+            TransferAction::Download { object, file_name } => (object, file_name),
+            _ => return Err(TransferError::InvalidToken),
+        };
+
+        let path = self.object_path(&object).map_err(TransferError::Io)?;
+        let file = match NamedFile::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(TransferError::NotFound)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let metadata_path = self.metadata_path(&object).map_err(TransferError::Io)?;
+        let metadata: Option<ObjectMetadata> = self
+            .read_metadata(&metadata_path)
+            .await
+            .map_err(TransferError::Io)?;
+        let content_type = metadata
+            .and_then(|metadata| ContentType::parse_flexible(&metadata.content_type))
+            .unwrap_or(ContentType::Binary);
+
+        Ok(LocalDownload {
+            file,
+            content_type,
+            disposition: Header::new("Content-Disposition", attachment_disposition(&file_name)),
+        })
+    }
+}
+
+/// Collects the files below `dir`, keyed by their path relative to `root`
+fn walk(root: &Path, dir: &Path, objects: &mut Vec<StoredObject>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(root, &entry.path(), objects)?;
+            continue;
+        }
+
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let key = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        objects.push(StoredObject {
+            key,
+            size: metadata.len() as i64,
+            last_modified: metadata.modified().ok(),
+        });
+    }
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl StorageBackend for LocalStorage {
+    async fn presign_upload(
+        &self,
+        object: &str,
+        size: i64,
+        content_type: &str,
+        display_name: &str,
+    ) -> Result<PresignedUpload, String> {
+        relative_path(object)?;
+
+        let action = TransferAction::Upload {
+            object: object.to_string(),
+            size: size as u64,
+            content_type: content_type.to_string(),
+            display_name: display_name.to_string(),
+        };
+        let expires_in = self.expiry.upload;
+        let headers = [
+            ("content-length".to_string(), size.to_string()),
+            ("content-type".to_string(), content_type.to_string()),
+        ];
+
+        Ok(PresignedUpload {
+            url: self.sign(action, expires_in)?,
+            headers: headers.into_iter().collect(),
+        })
+    }
+
+    async fn presign_download(&self, object: &str, file_name: &str) -> Result<String, String> {
+        let action = TransferAction::Download {
+            object: object.to_string(),
+            file_name: file_name.to_string(),
+        };
+        self.sign(action, self.expiry.download)
+    }
+
+    async fn head(&self, object: &str) -> Result<Option<ObjectInfo>, String> {
+        let size = match fs::metadata(self.object_path(object)?).await {
+            Ok(metadata) => metadata.len() as i64,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to look up object: {}", e)),
+        };
+        let metadata: Option<ObjectMetadata> =
+            self.read_metadata(&self.metadata_path(object)?).await?;
+
+        let (content_type, display_name) = match metadata {
+            Some(metadata) => (Some(metadata.content_type), metadata.display_name),
+            None => (None, None),
+        };
+
+        Ok(Some(ObjectInfo {
+            size,
+            content_type,
+            display_name,
+        }))
+    }
+
+    async fn delete(&self, object: &str) -> Result<(), String> {
+        for path in [self.object_path(object)?, self.metadata_path(object)?] {
+            match fs::remove_file(path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Failed to delete object: {}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>, String> {
+        let root = self.root.join("objects");
+
+        rocket::tokio::task::spawn_blocking(move || {
+            let mut objects = Vec::new();
+            walk(&root, &root, &mut objects)
+                .map_err(|e| format!("Failed to list objects: {}", e))?;
+            Ok(objects)
+        })
+        .await
+        .map_err(|e| format!("Listing objects failed: {}", e))?
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        object: &str,
+        content_type: &str,
+        display_name: &str,
+    ) -> Result<String, String> {
+        relative_path(object)?;
+
+        let upload_id = gen_id(16);
+        let metadata = UploadMetadata {
+            object: object.to_string(),
+            content_type: content_type.to_string(),
+            display_name: Some(display_name.to_string()),
+            initiated: now(),
+        };
+        let path = self.upload_path(&upload_id)?.join("upload.json");
+        self.write_metadata(&path, &metadata).await?;
+
+        Ok(upload_id)
+    }
+
+    async fn presign_part(
+        &self,
+        object: &str,
+        upload_id: &str,
+        part_number: i32,
+        size: i64,
+    ) -> Result<PresignedUpload, String> {
+        let action = TransferAction::UploadPart {
+            object: object.to_string(),
+            upload_id: upload_id.to_string(),
+            part_number,
+            size: size as u64,
+        };
+        let expires_in = self.expiry.part_upload;
+        let headers = [("content-length".to_string(), size.to_string())];
+
+        Ok(PresignedUpload {
+            url: self.sign(action, expires_in)?,
+            headers: headers.into_iter().collect(),
+        })
+    }
+
+    async fn list_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<Option<Vec<UploadedPart>>, String> {
+        if !is_upload_id(upload_id) || self.pending_upload(object, upload_id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let mut parts = Vec::new();
+        let mut entries = fs::read_dir(self.upload_path(upload_id)?)
+            .await
+            .map_err(|e| format!("Failed to list parts: {}", e))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| format!("Failed to list parts: {}", e))?
+        {
+            let name = entry.file_name();
+            let part_number = match name
+                .to_str()
+                .and_then(|name| name.strip_prefix("part-"))
+                .and_then(|number| number.parse::<i32>().ok())
+            {
+                Some(part_number) => part_number,
+                None => continue,
+            };
+            let metadata = entry
+                .metadata()
+                .await
+                .map_err(|e| format!("Failed to list parts: {}", e))?;
+
+            parts.push(UploadedPart {
+                part_number,
+                e_tag: part_number.to_string(),
+                size: metadata.len() as i64,
+            });
+        }
+
+        parts.sort_by_key(|part| part.part_number);
+        Ok(Some(parts))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), String> {
+        let upload = self
+            .pending_upload(object, upload_id)
+            .await?
+            .ok_or("Multipart upload not found")?;
+        let upload_path = self.upload_path(upload_id)?;
+
+        let temp_path = self.temp_path();
+        let result: std::io::Result<()> = async {
+            let mut file = fs::File::create(&temp_path).await?;
+            for part in parts {
+                let mut part_file =
+                    fs::File::open(upload_path.join(format!("part-{}", part.part_number))).await?;
+                io::copy(&mut part_file, &mut file).await?;
+            }
+            file.sync_all().await
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(format!("Failed to assemble multipart upload: {}", e));
+        }
+
+        let path = self.object_path(object)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+        fs::rename(&temp_path, &path)
+            .await
+            .map_err(|e| format!("Failed to store multipart upload: {}", e))?;
+
+        let metadata = ObjectMetadata {
+            content_type: upload.content_type,
+            display_name: upload.display_name,
+        };
+        self.write_metadata(&self.metadata_path(object)?, &metadata)
+            .await?;
+
+        fs::remove_dir_all(upload_path)
+            .await
+            .map_err(|e| format!("Failed to clean up multipart upload: {}", e))
+    }
+
+    async fn abort_multipart_upload(&self, object: &str, upload_id: &str) -> Result<bool, String> {
+        if !is_upload_id(upload_id) || self.pending_upload(object, upload_id).await?.is_none() {
+            return Ok(false);
+        }
+
+        fs::remove_dir_all(self.upload_path(upload_id)?)
+            .await
+            .map_err(|e| format!("Failed to abort multipart upload: {}", e))?;
+        Ok(true)
+    }
+
+    async fn list_multipart_uploads(&self) -> Result<Vec<PendingUpload>, String> {
+        let mut uploads = Vec::new();
+        let mut entries = fs::read_dir(self.root.join("uploads"))
+            .await
+            .map_err(|e| format!("Failed to list multipart uploads: {}", e))?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| format!("Failed to list multipart uploads: {}", e))?
+        {
+            let upload_id = entry.file_name().to_string_lossy().to_string();
+            let metadata: Option<UploadMetadata> = self
+                .read_metadata(&entry.path().join("upload.json"))
+                .await?;
+
+            if let Some(metadata) = metadata {
+                uploads.push(PendingUpload {
+                    key: metadata.object,
+                    upload_id,
+                    initiated: Some(UNIX_EPOCH + Duration::from_secs(metadata.initiated)),
+                });
+            }
+        }
+
+        Ok(uploads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ops::Deref;
+
+    /// Storage in a temporary directory, which is removed along with it
+    struct TestStorage(LocalStorage);
+
+    impl Deref for TestStorage {
+        type Target = LocalStorage;
+
+        fn deref(&self) -> &LocalStorage {
+            &self.0
+        }
+    }
+
+    impl Drop for TestStorage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0.root);
+        }
+    }
+
+    fn storage_with_secret(secret: &[u8]) -> TestStorage {
+        let config = LocalStorageConfig {
+            path: env::temp_dir().join(format!("iclip-test-{}", gen_id(10))),
+            ..LocalStorageConfig::default()
+        };
+        TestStorage(LocalStorage::with_secret(&config, PresignExpiry::default(), secret).unwrap())
+    }
+
+    fn storage() -> TestStorage {
+        storage_with_secret(b"test secret")
+    }
+
+    fn download(object: &str) -> TransferAction {
+        TransferAction::Download {
+            object: object.to_string(),
+            file_name: "report.pdf".to_string(),
+        }
+    }
+
+    /// The token part of a transfer URL
+    fn token(url: &str) -> &str {
+        url.rsplit('/').next().unwrap()
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let storage = storage();
+        let url = storage.sign(download("abc/report.pdf"), 60).unwrap();
+        assert!(url.starts_with("http://localhost:8000/api/storage/"));
+
+        match storage.verify(token(&url)) {
+            Ok(TransferAction::Download { object, file_name }) => {
+                assert_eq!(object, "abc/report.pdf");
+                assert_eq!(file_name, "report.pdf");
+            }
+            _ => panic!("Token did not verify"),
+        }
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let storage = storage();
+        let url = storage.sign(download("abc/report.pdf"), 60).unwrap();
+        let (payload, signature) = token(&url).split_once('.').unwrap();
+
+        // Pointing the token at another object
+        let forged = String::from_utf8(Base64UrlUnpadded::decode_vec(payload).unwrap())
+            .unwrap()
+            .replace("abc/report.pdf", "xyz/secret.pdf");
+        let forged = Base64UrlUnpadded::encode_string(forged.as_bytes());
+        let tampered = [
+            format!("{}.{}", forged, signature),
+            format!("{}.{}", payload, &signature[1..]),
+            format!("{}.{}", payload, Base64UrlUnpadded::encode_string(&[0; 32])),
+            payload.to_string(),
+            "not a token".to_string(),
+            String::new(),
+        ];
+        for token in tampered {
+            assert!(
+                matches!(storage.verify(&token), Err(TransferError::InvalidToken)),
+                "{:?}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn tokens_of_other_secrets_are_rejected() {
+        let url = storage().sign(download("abc/report.pdf"), 60).unwrap();
+        assert!(matches!(
+            storage_with_secret(b"another secret").verify(token(&url)),
+            Err(TransferError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let storage = storage();
+        let url = storage
+            .sign_token(&Token {
+                action: download("abc/report.pdf"),
+                expires: now() - 1,
+            })
+            .unwrap();
+        assert!(matches!(
+            storage.verify(token(&url)),
+            Err(TransferError::ExpiredToken)
+        ));
+    }
+
+    #[test]
+    fn keys_outside_the_storage_directory_are_rejected() {
+        assert!(relative_path("abc/report.pdf").is_ok());
+        for key in [
+            "",
+            "../report.pdf",
+            "abc/../../report.pdf",
+            "/etc/passwd",
+            "./abc",
+        ] {
+            assert!(relative_path(key).is_err(), "{:?}", key);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::env;
+use std::time::{Duration, SystemTime};
+
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::presigning::config::PresigningConfig;
+use aws_sdk_s3::presigning::request::PresignedRequest;
+use aws_sdk_s3::types::SdkError;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::{Endpoint, Region};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+
+use super::{
+    attachment_disposition, ObjectInfo, PendingUpload, PresignExpiry, PresignedUpload,
+    S3StorageConfig, StorageBackend, StoredObject, UploadedPart,
+};
+
+/// Creates the S3 client, taking the region and credentials from the environment
+/// and the endpoint from the config or `CUSTOM_ENDPOINT`
+pub async fn create_storage_client(config: &S3StorageConfig) -> Result<Client, String> {
+    let shared_config = aws_config::from_env()
+        .region(RegionProviderChain::default_provider().or_else(Region::new(config.region.clone())))
+        .load()
+        .await;
+
+    // Buckets are always addressed by path, which is what S3 compatible services expect
+    let endpoint = config
+        .endpoint
+        .clone()
+        .or_else(|| env::var("CUSTOM_ENDPOINT").ok());
+    if let Some(endpoint_str) = endpoint {
+        let region = shared_config
+            .region()
+            .cloned()
+            .unwrap_or_else(|| Region::new(config.region.clone()));
+        let credentials_provider = shared_config
+            .credentials_provider()
+            .ok_or("No storage credentials found")?
+            .clone();
+        let endpoint_uri = endpoint_str
+            .parse()
+            .map_err(|e| format!("Invalid endpoint {}: {}", endpoint_str, e))?;
+
+        let client_config = aws_sdk_s3::Config::builder()
+            .region(region)
+            .endpoint_resolver(Endpoint::immutable(endpoint_uri))
+            .credentials_provider(credentials_provider)
+            .build();
+
+        return Ok(Client::from_conf(client_config));
+    }
+
+    Ok(Client::new(&shared_config))
+}
+
+/// User metadata key the display name is stored under, sent as `x-amz-meta-display-name`
+const DISPLAY_NAME_METADATA: &str = "display-name";
+
+/// S3 only allows ASCII in metadata, so display names are stored percent-encoded
+fn encode_display_name(display_name: &str) -> String {
+    utf8_percent_encode(display_name, NON_ALPHANUMERIC).to_string()
+}
+
+fn decode_display_name(value: &str) -> Option<String> {
+    percent_decode_str(value)
+        .decode_utf8()
+        .ok()
+        .map(|name| name.to_string())
+}
+
+fn presigning_config(expires_in: u64) -> Result<PresigningConfig, String> {
+    PresigningConfig::expires_in(Duration::from_secs(expires_in))
+        .map_err(|e| format!("Failed to create presigning config: {}", e))
+}
+
+impl From<PresignedRequest> for PresignedUpload {
+    fn from(request: PresignedRequest) -> Self {
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+
+        PresignedUpload {
+            url: request.uri().to_string(),
+            headers,
+        }
+    }
+}
+
+/// Keeps files in an S3 bucket, which clients upload to and download from directly
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    key_prefix: String,
+    expiry: PresignExpiry,
+}
+
+impl S3Storage {
+    pub fn new(client: Client, config: &S3StorageConfig, expiry: PresignExpiry) -> Self {
+        S3Storage {
+            client,
+            bucket: config.bucket.clone(),
+            key_prefix: config.key_prefix.clone(),
+            expiry,
+        }
+    }
+
+    /// The key an object is stored under in the bucket
+    fn key(&self, object: &str) -> String {
+        format!("{}{}", self.key_prefix, object)
+    }
+
+    /// The object a key in the bucket belongs to, if it is one of ours
+    fn object<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(&self.key_prefix)
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Storage {
+    async fn presign_upload(
+        &self,
+        object: &str,
+        size: i64,
+        content_type: &str,
+        display_name: &str,
+    ) -> Result<PresignedUpload, String> {
+        match self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .content_length(size)
+            .content_type(content_type)
+            .metadata(DISPLAY_NAME_METADATA, encode_display_name(display_name))
+            .presigned(presigning_config(self.expiry.upload)?)
+            .await
+        {
+            Ok(presigned_request) => Ok(presigned_request.into()),
+            Err(e) => Err(format!("Failed to create presigned URL: {}", e)),
+        }
+    }
+
+    async fn presign_download(&self, object: &str, file_name: &str) -> Result<String, String> {
+        match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .response_content_disposition(attachment_disposition(file_name))
+            .presigned(presigning_config(self.expiry.download)?)
+            .await
+        {
+            Ok(presigned_request) => Ok(presigned_request.uri().to_string()),
+            Err(e) => Err(format!("Failed to create presigned URL: {}", e)),
+        }
+    }
+
+    async fn head(&self, object: &str) -> Result<Option<ObjectInfo>, String> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .send()
+            .await
+        {
+            Ok(output) => Ok(Some(ObjectInfo {
+                size: output.content_length(),
+                content_type: output.content_type().map(str::to_string),
+                display_name: output
+                    .metadata()
+                    .and_then(|metadata| metadata.get(DISPLAY_NAME_METADATA))
+                    .and_then(|value| decode_display_name(value)),
+            })),
+            Err(SdkError::ServiceError { err, .. }) if err.is_not_found() => Ok(None),
+            Err(e) => Err(format!("Failed to look up object: {}", e)),
+        }
+    }
+
+    async fn delete(&self, object: &str) -> Result<(), String> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to delete object: {}", e))
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>, String> {
+        let mut objects = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&self.key_prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .map_err(|e| format!("Failed to list objects: {}", e))?;
+
+            for object in output.contents().unwrap_or_default() {
+                if let Some(key) = object.key().and_then(|key| self.object(key)) {
+                    objects.push(StoredObject {
+                        key: key.to_string(),
+                        size: object.size(),
+                        last_modified: object
+                            .last_modified()
+                            .and_then(|time| SystemTime::try_from(*time).ok()),
+                    });
+                }
+            }
+
+            match output.next_continuation_token() {
+                Some(token) if output.is_truncated() => {
+                    continuation_token = Some(token.to_string())
+                }
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        object: &str,
+        content_type: &str,
+        display_name: &str,
+    ) -> Result<String, String> {
+        match self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .content_type(content_type)
+            .metadata(DISPLAY_NAME_METADATA, encode_display_name(display_name))
+            .send()
+            .await
+        {
+            Ok(output) => output
+                .upload_id()
+                .map(str::to_string)
+                .ok_or_else(|| "Multipart upload created without an upload ID".to_string()),
+            Err(e) => Err(format!("Failed to create multipart upload: {}", e)),
+        }
+    }
+
+    async fn presign_part(
+        &self,
+        object: &str,
+        upload_id: &str,
+        part_number: i32,
+        size: i64,
+    ) -> Result<PresignedUpload, String> {
+        match self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .content_length(size)
+            .presigned(presigning_config(self.expiry.part_upload)?)
+            .await
+        {
+            Ok(presigned_request) => Ok(presigned_request.into()),
+            Err(e) => Err(format!("Failed to create presigned URL: {}", e)),
+        }
+    }
+
+    async fn list_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+    ) -> Result<Option<Vec<UploadedPart>>, String> {
+        let mut parts = Vec::new();
+        let mut marker: Option<String> = None;
+
+        loop {
+            let output = match self
+                .client
+                .list_parts()
+                .bucket(&self.bucket)
+                .key(self.key(object))
+                .upload_id(upload_id)
+                .set_part_number_marker(marker.take())
+                .send()
+                .await
+            {
+                Ok(output) => output,
+                Err(SdkError::ServiceError { err, .. }) if err.code() == Some("NoSuchUpload") => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(format!("Failed to list parts: {}", e)),
+            };
+
+            for part in output.parts().unwrap_or_default() {
+                parts.push(UploadedPart {
+                    part_number: part.part_number(),
+                    e_tag: part.e_tag().unwrap_or_default().to_string(),
+                    size: part.size(),
+                });
+            }
+
+            match output.next_part_number_marker() {
+                Some(next) if output.is_truncated() => marker = Some(next.to_string()),
+                _ => break,
+            }
+        }
+
+        Ok(Some(parts))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        object: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), String> {
+        let completed_parts = parts
+            .iter()
+            .map(|part| {
+                CompletedPart::builder()
+                    .part_number(part.part_number)
+                    .e_tag(&part.e_tag)
+                    .build()
+            })
+            .collect();
+        let multipart_upload = CompletedMultipartUpload::builder()
+            .set_parts(Some(completed_parts))
+            .build();
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .upload_id(upload_id)
+            .multipart_upload(multipart_upload)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("Failed to complete multipart upload: {}", e))
+    }
+
+    async fn abort_multipart_upload(&self, object: &str, upload_id: &str) -> Result<bool, String> {
+        match self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(self.key(object))
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError { err, .. }) if err.is_no_such_upload() => Ok(false),
+            Err(e) => Err(format!("Failed to abort multipart upload: {}", e)),
+        }
+    }
+
+    async fn list_multipart_uploads(&self) -> Result<Vec<PendingUpload>, String> {
+        let mut uploads = Vec::new();
+        let mut key_marker: Option<String> = None;
+        let mut upload_id_marker: Option<String> = None;
+
+        loop {
+            let output = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .prefix(&self.key_prefix)
+                .set_key_marker(key_marker.take())
+                .set_upload_id_marker(upload_id_marker.take())
+                .send()
+                .await
+                .map_err(|e| format!("Failed to list multipart uploads: {}", e))?;
+
+            for upload in output.uploads().unwrap_or_default() {
+                let key = upload.key().and_then(|key| self.object(key));
+                if let (Some(key), Some(upload_id)) = (key, upload.upload_id()) {
+                    uploads.push(PendingUpload {
+                        key: key.to_string(),
+                        upload_id: upload_id.to_string(),
+                        initiated: upload
+                            .initiated()
+                            .and_then(|time| SystemTime::try_from(*time).ok()),
+                    });
+                }
+            }
+
+            if !output.is_truncated() {
+                break;
+            }
+            key_marker = output.next_key_marker().map(str::to_string);
+            upload_id_marker = output.next_upload_id_marker().map(str::to_string);
+        }
+
+        Ok(uploads)
+    }
+}
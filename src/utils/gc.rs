@@ -0,0 +1,313 @@
+use serde::Deserialize;
+
+use std::time::{Duration, SystemTime};
+
+use super::db::{self, with_connection, DbPool};
+use super::filename;
+use super::files::Storage;
+use super::maintenance::JobOutcome;
+use crate::models::ClipType;
+
+/// Number of object keys looked up in the database at once
+const LOOKUP_BATCH_SIZE: usize = 1000;
+
+/// Garbage collection settings, read from the `gc` section of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GcConfig {
+    /// Only log what would be removed from storage instead of removing it
+    /// Expired clips are still marked as deleted, their objects are swept as orphans later
+    pub dry_run: bool,
+    /// Seconds an object or multipart upload without a clip is kept around before it is removed,
+    /// which gives clients time to finish their uploads
+    pub orphan_age: u64,
+    /// Seconds the record of an expired clip is kept before it is purged from `clips`
+    pub retention: u64,
+    /// Move purged clips to `clips_archive` rather than discarding them
+    pub archive: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            dry_run: false,
+            orphan_age: 24 * 60 * 60,
+            retention: 30 * 24 * 60 * 60,
+            archive: true,
+        }
+    }
+}
+
+/// Longest the records of expired clips can be kept for
+const MAX_RETENTION: u64 = 10 * 365 * 24 * 60 * 60;
+/// Longest objects without a clip can be kept for
+const MAX_ORPHAN_AGE: u64 = 10 * 365 * 24 * 60 * 60;
+
+impl GcConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.retention > MAX_RETENTION {
+            return Err(format!(
+                "retention must be at most {} seconds",
+                MAX_RETENTION
+            ));
+        }
+        if self.orphan_age > MAX_ORPHAN_AGE {
+            return Err(format!(
+                "orphan_age must be at most {} seconds",
+                MAX_ORPHAN_AGE
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Objects removed from storage by one run
+#[derive(Default)]
+struct Reclaimed {
+    objects: usize,
+    bytes: u64,
+    /// Objects that couldn't be removed, along with failed lookups
+    failures: usize,
+}
+
+impl Reclaimed {
+    fn add(&mut self, bytes: i64) {
+        self.objects += 1;
+        self.bytes += bytes.max(0) as u64;
+    }
+
+    /// Reports the run as failed if anything couldn't be removed
+    fn outcome(&self, summary: String, dry_run: bool) -> JobOutcome {
+        let summary = format!(
+            "{}, {} {} bytes from {} objects",
+            summary,
+            if dry_run {
+                "would reclaim"
+            } else {
+                "reclaimed"
+            },
+            self.bytes,
+            self.objects
+        );
+
+        if self.failures > 0 {
+            Err(format!("{} failures, {}", self.failures, summary))
+        } else {
+            Ok(summary)
+        }
+    }
+}
+
+/// Marks expired clips as deleted and removes their files
+/// Files that fail to be deleted are left for the orphan sweep
+pub async fn delete_expired_clips(pool: DbPool, storage: Storage, config: GcConfig) -> JobOutcome {
+    let deleted = with_connection(&pool, db::tombstone_expired_clips)
+        .await
+        .map_err(|e| format!("Failed to collect expired clips: {}", e))?;
+
+    let mut reclaimed = Reclaimed::default();
+    for clip in &deleted {
+        if clip.clip_type != ClipType::File {
+            continue;
+        }
+        if remove_object(&storage, &clip.url, config.dry_run).await {
+            reclaimed.add(clip.file_size.unwrap_or_default());
+        } else {
+            reclaimed.failures += 1;
+        }
+    }
+
+    reclaimed.outcome(
+        format!("deleted {} expired clips", deleted.len()),
+        config.dry_run,
+    )
+}
+
+/// Purges the records of clips that expired more than `retention` ago, archiving them if enabled,
+/// and frees the codes of clips that expired more than `code_cooldown` ago
+pub async fn purge_tombstones(pool: DbPool, config: GcConfig, code_cooldown: u64) -> JobOutcome {
+    let now = chrono::Local::now().naive_local();
+    let cutoff = now - chrono::Duration::seconds(config.retention as i64);
+    let code_cutoff = now - chrono::Duration::seconds(code_cooldown as i64);
+
+    let (purged, released) = with_connection(&pool, move |connection| {
+        let purged = db::purge_tombstones(connection, cutoff, config.archive)?;
+        let released = db::release_retired_codes(connection, code_cutoff)?;
+        Ok((purged, released))
+    })
+    .await?;
+
+    Ok(format!(
+        "{} {} expired clips, released {} retired codes",
+        if config.archive { "archived" } else { "purged" },
+        purged,
+        released
+    ))
+}
+
+/// Whether an object or multipart upload is left for the sweep to look up
+/// Only uploads older than `cutoff` are, anything else in the bucket isn't ours to remove
+fn is_sweepable(key: &str, time: Option<SystemTime>, cutoff: SystemTime) -> bool {
+    filename::is_upload_key(key) && time.is_some_and(|time| time < cutoff)
+}
+
+/// Sweeps storage for uploaded objects and multipart uploads older than `orphan_age` that no clip
+/// refers to
+/// Every failure is logged as it happens and the sweep carries on with the next object
+pub async fn sweep_orphans(pool: DbPool, storage: Storage, config: GcConfig) -> JobOutcome {
+    let mut reclaimed = Reclaimed::default();
+    let cutoff = SystemTime::now()
+        .checked_sub(Duration::from_secs(config.orphan_age))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    match storage.list().await {
+        Ok(objects) => {
+            let old_objects: Vec<_> = objects
+                .into_iter()
+                .filter(|object| is_sweepable(&object.key, object.last_modified, cutoff))
+                .collect();
+
+            for batch in old_objects.chunks(LOOKUP_BATCH_SIZE) {
+                let keys: Vec<String> = batch.iter().map(|object| object.key.clone()).collect();
+                let referenced = match with_connection(&pool, move |connection| {
+                    db::referenced_object_keys(connection, &keys)
+                })
+                .await
+                {
+                    Ok(referenced) => referenced,
+                    Err(err) => {
+                        error!("Failed to look up stored objects: {}", err);
+                        reclaimed.failures += 1;
+                        break;
+                    }
+                };
+
+                for object in batch {
+                    if referenced.contains(&object.key) {
+                        continue;
+                    }
+                    if remove_object(&storage, &object.key, config.dry_run).await {
+                        reclaimed.add(object.size);
+                    } else {
+                        reclaimed.failures += 1;
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            error!("{}", err);
+            reclaimed.failures += 1;
+        }
+    }
+
+    let mut aborted_uploads = 0;
+    match storage.list_multipart_uploads().await {
+        Ok(uploads) => {
+            for upload in uploads
+                .into_iter()
+                .filter(|upload| is_sweepable(&upload.key, upload.initiated, cutoff))
+            {
+                let size = match storage.list_parts(&upload.key, &upload.upload_id).await {
+                    Ok(parts) => parts.unwrap_or_default().iter().map(|part| part.size).sum(),
+                    Err(err) => {
+                        error!("{}", err);
+                        0
+                    }
+                };
+
+                if config.dry_run {
+                    info!("Would abort multipart upload of {}", upload.key);
+                    reclaimed.add(size);
+                    aborted_uploads += 1;
+                    continue;
+                }
+
+                match storage
+                    .abort_multipart_upload(&upload.key, &upload.upload_id)
+                    .await
+                {
+                    Ok(_) => {
+                        reclaimed.add(size);
+                        aborted_uploads += 1;
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                        reclaimed.failures += 1;
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            error!("{}", err);
+            reclaimed.failures += 1;
+        }
+    }
+
+    // Records of uploads that were never completed are only needed until their parts are gone
+    if !config.dry_run {
+        let cutoff = chrono::Local::now().naive_local()
+            - chrono::Duration::seconds(config.orphan_age as i64);
+        if let Err(err) = with_connection(&pool, move |connection| {
+            db::delete_stale_multipart_uploads(connection, cutoff)
+        })
+        .await
+        {
+            error!("Failed to delete stale multipart upload records: {}", err);
+            reclaimed.failures += 1;
+        }
+    }
+
+    reclaimed.outcome(
+        format!("aborted {} abandoned multipart uploads", aborted_uploads),
+        config.dry_run,
+    )
+}
+
+/// Deletes an object, or only logs it in a dry run
+/// Returns whether the object counts as reclaimed
+async fn remove_object(storage: &Storage, object: &str, dry_run: bool) -> bool {
+    if dry_run {
+        info!("Would delete {}", object);
+        return true;
+    }
+
+    match storage.delete(object).await {
+        Ok(()) => true,
+        Err(err) => {
+            error!("{}", err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::files::StoredObject;
+
+    #[test]
+    fn only_old_uploads_are_swept() {
+        let cutoff = SystemTime::now() - Duration::from_secs(60 * 60);
+        let old = Some(cutoff - Duration::from_secs(1));
+        let listing = [
+            ("k3j9x0a2bq/report.pdf", old),
+            ("k3j9x0a2bq/recent.pdf", Some(SystemTime::now())),
+            ("k3j9x0a2bq/unknown.pdf", None),
+            ("backups/2026-10-01.sql.gz", old),
+            ("favicon.ico", old),
+            ("K3J9X0A2BQ/report.pdf", old),
+        ]
+        .map(|(key, last_modified)| StoredObject {
+            key: key.to_string(),
+            size: 0,
+            last_modified,
+        });
+
+        let swept: Vec<_> = listing
+            .iter()
+            .filter(|object| is_sweepable(&object.key, object.last_modified, cutoff))
+            .map(|object| object.key.as_str())
+            .collect();
+        assert_eq!(swept, ["k3j9x0a2bq/report.pdf"]);
+    }
+}
@@ -1,10 +1,31 @@
 use rand::Rng;
+use serde::Deserialize;
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const DEFAULT_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Crockford's base32, leaving out `i`, `l`, `o` and `u`
+const CROCKFORD_ALPHABET: &str = "0123456789abcdefghjkmnpqrstvwxyz";
+
+/// Insert attempts after which a single insert moves on to a longer code
+const ATTEMPTS_PER_LENGTH: usize = 3;
+
+/// Longest codes of expired clips can be held back for
+const MAX_COOLDOWN: u64 = 10 * 365 * 24 * 60 * 60;
 
 /// Generate an alphanumeric ID, n letters long
 pub fn gen_id(length: usize) -> String {
+    gen_from(DEFAULT_ALPHABET, length)
+}
+
+/// Generate an ID from the characters of `alphabet`, n letters long
+fn gen_from(alphabet: &str, length: usize) -> String {
     let mut code = String::new();
     let mut rng = rand::thread_rng();
-    let chars: Vec<char> = "abcdefghijklmnopqrstuvwxyz0123456789".chars().collect();
+    let chars: Vec<char> = alphabet.chars().collect();
 
     for _ in 0..length {
         let random_char = rng.gen_range(0..chars.len());
@@ -13,3 +34,279 @@ pub fn gen_id(length: usize) -> String {
 
     code
 }
+
+/// How clip codes are spelled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeScheme {
+    /// Any characters of the configured alphabet, matched exactly
+    #[default]
+    Alphanumeric,
+    /// Crockford's base32, which tolerates the typos of codes read aloud or off a screen
+    Crockford,
+}
+
+/// Clip code settings, read from the `codes` section of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CodeConfig {
+    pub scheme: CodeScheme,
+    /// Characters codes are made of, ignored by the crockford scheme
+    pub alphabet: String,
+    /// Length new codes start out with
+    pub min_length: usize,
+    /// Length new codes never grow beyond
+    pub max_length: usize,
+    /// Number of inserts over which the collision rate is measured
+    pub collision_window: u32,
+    /// Share of colliding inserts above which codes get longer
+    pub collision_threshold: f64,
+    /// Seconds after a clip expires before its code can be given to a new clip
+    pub cooldown: u64,
+}
+
+impl Default for CodeConfig {
+    fn default() -> Self {
+        CodeConfig {
+            scheme: CodeScheme::default(),
+            alphabet: DEFAULT_ALPHABET.to_string(),
+            min_length: 5,
+            max_length: 10,
+            collision_window: 100,
+            collision_threshold: 0.1,
+            cooldown: 90 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl CodeConfig {
+    /// Checks that the settings can produce codes at all
+    pub fn validate(&self) -> Result<(), String> {
+        if self.alphabet.chars().count() < 2 {
+            return Err("The code alphabet needs at least 2 characters".to_string());
+        }
+        // Codes end up in paths and query strings, and the keyspace is counted by character
+        if !self.alphabet.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("The code alphabet may only contain ASCII letters and digits".to_string());
+        }
+        let distinct: HashSet<char> = self
+            .alphabet
+            .chars()
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        if distinct.len() != self.alphabet.len() {
+            return Err(
+                "The code alphabet must not repeat characters, regardless of case".to_string(),
+            );
+        }
+        if self.min_length == 0 || self.min_length > self.max_length {
+            return Err("Code lengths must satisfy 0 < min_length <= max_length".to_string());
+        }
+        if self.cooldown > MAX_COOLDOWN {
+            return Err(format!("cooldown must be at most {} seconds", MAX_COOLDOWN));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct CollisionStats {
+    attempts: u32,
+    collisions: u32,
+}
+
+/// Generates clip codes, making them longer when too many of them collide
+pub struct CodeGenerator {
+    config: CodeConfig,
+    length: AtomicUsize,
+    stats: Mutex<CollisionStats>,
+}
+
+impl CodeGenerator {
+    pub fn new(mut config: CodeConfig) -> Self {
+        if config.scheme == CodeScheme::Crockford {
+            config.alphabet = CROCKFORD_ALPHABET.to_string();
+        }
+
+        CodeGenerator {
+            length: AtomicUsize::new(config.min_length),
+            config,
+            stats: Mutex::new(CollisionStats::default()),
+        }
+    }
+
+    /// Picks up the length codes were grown to before, e.g. by an earlier run or another instance
+    /// Starts at the shortest length whose keyspace is no more crowded than `collision_threshold`,
+    /// with `taken` counting the codes of a length that are already in use
+    pub fn resume<E>(&self, mut taken: impl FnMut(usize) -> Result<i64, E>) -> Result<(), E> {
+        let alphabet = self.config.alphabet.chars().count() as f64;
+        let mut length = self.config.min_length;
+        while length < self.config.max_length {
+            let occupancy = taken(length)? as f64 / alphabet.powi(length as i32);
+            if occupancy <= self.config.collision_threshold {
+                break;
+            }
+            length += 1;
+        }
+
+        if length > self.config.min_length {
+            info!(
+                "Clip codes are crowded, starting with {} characters",
+                length
+            );
+        }
+        self.length.store(length, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The length new codes are currently generated with
+    pub fn length(&self) -> usize {
+        self.length.load(Ordering::Relaxed)
+    }
+
+    /// Seconds codes of expired clips are held back before they are handed out again
+    pub fn cooldown(&self) -> u64 {
+        self.config.cooldown
+    }
+
+    /// Generates a code for the given attempt at inserting a clip
+    /// Repeated attempts get longer codes, so a single insert gets out of a crowded keyspace quickly
+    pub fn generate(&self, attempt: usize) -> String {
+        let length = (self.length() + attempt / ATTEMPTS_PER_LENGTH).min(self.config.max_length);
+        gen_from(&self.config.alphabet, length)
+    }
+
+    /// Records whether an insert attempt collided with an existing code
+    pub fn record(&self, collided: bool) {
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        stats.attempts += 1;
+        if collided {
+            stats.collisions += 1;
+        }
+
+        if stats.attempts < self.config.collision_window {
+            return;
+        }
+
+        let rate = f64::from(stats.collisions) / f64::from(stats.attempts);
+        *stats = CollisionStats::default();
+
+        let length = self.length();
+        if rate > self.config.collision_threshold && length < self.config.max_length {
+            warn!(
+                "{:.0}% of clip codes collided, growing codes to {} characters",
+                rate * 100.0,
+                length + 1
+            );
+            self.length.store(length + 1, Ordering::Relaxed);
+        }
+    }
+
+    /// Brings user input into the canonical spelling of a code
+    ///
+    /// With the crockford scheme this is case-insensitive, ignores hyphens
+    /// and reads `o` as `0` and `i` or `l` as `1`.
+    pub fn normalize(&self, code: &str) -> String {
+        match self.config.scheme {
+            // Letters are brought into the case the alphabet has them in, so `Meeting` and
+            // `meeting` are the same code rather than two that can't be told apart when typed
+            CodeScheme::Alphanumeric => code
+                .chars()
+                .map(|c| {
+                    let alphabet = &self.config.alphabet;
+                    if alphabet.contains(c) {
+                        c
+                    } else if alphabet.contains(c.to_ascii_lowercase()) {
+                        c.to_ascii_lowercase()
+                    } else if alphabet.contains(c.to_ascii_uppercase()) {
+                        c.to_ascii_uppercase()
+                    } else {
+                        c
+                    }
+                })
+                .collect(),
+            CodeScheme::Crockford => code
+                .chars()
+                .filter(|c| *c != '-')
+                .map(|c| match c.to_ascii_lowercase() {
+                    'o' => '0',
+                    'i' | 'l' => '1',
+                    c => c,
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether `code` could have been generated with the current settings
+    /// Codes are expected in their normalized form, so letters in the wrong case are rejected
+    pub fn is_valid(&self, code: &str) -> bool {
+        let length = code.chars().count();
+        length >= self.config.min_length
+            && length <= self.config.max_length
+            && code.chars().all(|c| self.config.alphabet.contains(c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> CodeGenerator {
+        CodeGenerator::new(CodeConfig {
+            alphabet: "ab".to_string(),
+            min_length: 2,
+            max_length: 5,
+            ..CodeConfig::default()
+        })
+    }
+
+    #[test]
+    fn validate_checks_the_alphabet() {
+        assert!(CodeConfig::default().validate().is_ok());
+        for alphabet in ["a", "abca", "abcA", "ab/c", "ab c", "ab?#", "abé"] {
+            let config = CodeConfig {
+                alphabet: alphabet.to_string(),
+                ..CodeConfig::default()
+            };
+            assert!(config.validate().is_err(), "{:?}", alphabet);
+        }
+    }
+
+    #[test]
+    fn codes_take_the_case_of_the_alphabet() {
+        let codes = CodeGenerator::new(CodeConfig::default());
+        assert_eq!(codes.normalize("Meeting"), "meeting");
+        assert!(codes.is_valid("meeting"));
+        assert!(!codes.is_valid("Meeting"));
+
+        let codes = CodeGenerator::new(CodeConfig {
+            alphabet: "ABCDEFGHIJKLMNOPQRSTUVWXYZ".to_string(),
+            ..CodeConfig::default()
+        });
+        assert_eq!(codes.normalize("Meeting"), "MEETING");
+        assert!(!codes.is_valid("meeting"));
+    }
+
+    #[test]
+    fn resume_skips_crowded_lengths() {
+        let codes = generator();
+        // All 4 codes of length 2 and 2 of the 8 of length 3 are taken
+        let taken = |length| Ok::<_, ()>([0, 0, 4, 2, 0, 0][length]);
+        codes.resume(taken).unwrap();
+        assert_eq!(codes.length(), 4);
+    }
+
+    #[test]
+    fn resume_stays_at_min_length_when_empty() {
+        let codes = generator();
+        codes.resume(|_| Ok::<_, ()>(0)).unwrap();
+        assert_eq!(codes.length(), 2);
+    }
+
+    #[test]
+    fn resume_stops_at_max_length() {
+        let codes = generator();
+        codes.resume(|length| Ok::<_, ()>(1 << length)).unwrap();
+        assert_eq!(codes.length(), 5);
+    }
+}
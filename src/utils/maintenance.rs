@@ -0,0 +1,347 @@
+use chrono::{DateTime, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::futures::future::BoxFuture;
+use rocket::tokio::sync::{watch, Notify};
+use rocket::tokio::task::JoinHandle;
+use rocket::tokio::time::{self, MissedTickBehavior};
+use rocket::{Orbit, Rocket};
+use serde::{Deserialize, Serialize};
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::db;
+
+/// What a job reports when it is done, e.g. how many rows it deleted
+pub type JobOutcome = Result<String, String>;
+
+/// Seconds between runs of each job, read from the `jobs` section of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// Deleting expired clips and their files
+    pub expired_clips: u64,
+    /// Removing stored objects and multipart uploads no clip refers to
+    pub orphan_sweep: u64,
+    /// Purging or archiving the records of clips past their retention
+    pub tombstone_purge: u64,
+    /// Checking that the database can be reached
+    pub pool_health: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            expired_clips: 60 * 60,
+            orphan_sweep: 6 * 60 * 60,
+            tombstone_purge: 24 * 60 * 60,
+            pool_health: 60,
+        }
+    }
+}
+
+impl JobsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let intervals = [
+            ("expired_clips", self.expired_clips),
+            ("orphan_sweep", self.orphan_sweep),
+            ("tombstone_purge", self.tombstone_purge),
+            ("pool_health", self.pool_health),
+        ];
+        for (name, interval) in intervals {
+            if interval == 0 {
+                return Err(format!("{} must be at least 1 second", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the last run of a job ended
+#[derive(Clone, Serialize)]
+#[serde(tag = "status", content = "message", rename_all = "lowercase")]
+pub enum JobResult {
+    Success(String),
+    Error(String),
+    /// Another instance is running the job or ran it recently
+    Skipped(String),
+}
+
+impl From<JobOutcome> for JobResult {
+    fn from(outcome: JobOutcome) -> Self {
+        match outcome {
+            Ok(summary) => JobResult::Success(summary),
+            Err(err) => JobResult::Error(err),
+        }
+    }
+}
+
+#[derive(Default)]
+struct JobState {
+    running: bool,
+    last_run: Option<DateTime<Utc>>,
+    last_duration: Option<Duration>,
+    last_result: Option<JobResult>,
+}
+
+/// A snapshot of a job, as shown by the admin endpoint
+#[derive(Serialize)]
+pub struct JobStatus {
+    pub name: &'static str,
+    /// Seconds between scheduled runs
+    pub interval: u64,
+    pub exclusive: bool,
+    pub running: bool,
+    /// When the last run started
+    pub last_run: Option<DateTime<Utc>>,
+    /// How long the last run took, in milliseconds
+    pub last_duration: Option<u128>,
+    pub last_result: Option<JobResult>,
+}
+
+/// A maintenance task that is run periodically
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    /// Only one instance sharing the database runs the job per interval
+    exclusive: bool,
+    run: Box<dyn Fn() -> BoxFuture<'static, JobOutcome> + Send + Sync>,
+    /// Wakes the job up to run outside of its schedule
+    trigger: Notify,
+    state: Mutex<JobState>,
+}
+
+impl Job {
+    fn state(&self) -> MutexGuard<'_, JobState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn status(&self) -> JobStatus {
+        let state = self.state();
+        JobStatus {
+            name: self.name,
+            interval: self.interval.as_secs(),
+            exclusive: self.exclusive,
+            running: state.running,
+            last_run: state.last_run,
+            last_duration: state.last_duration.map(|duration| duration.as_millis()),
+            last_result: state.last_result.clone(),
+        }
+    }
+}
+
+/// Runs background jobs such as garbage collection on their schedules
+/// Jobs start once the server has lifted off and are stopped when it shuts down
+/// Clones share the same jobs, so one can be attached as a fairing and another managed as state
+#[derive(Clone)]
+pub struct Maintenance {
+    jobs: Vec<Arc<Job>>,
+    shutdown: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Maintenance {
+            jobs: Vec::new(),
+            shutdown: Arc::new(watch::channel(false).0),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Registers a job that is run right after liftoff and then every `interval`
+    /// A run that takes longer than `interval` delays the next one rather than overlapping it
+    pub fn add_job<F, Fut>(&mut self, name: &'static str, interval: Duration, run: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = JobOutcome> + Send + 'static,
+    {
+        self.push_job(name, interval, false, run);
+    }
+
+    /// Registers a job like `add_job`, that only runs while this instance holds its advisory lock
+    /// Instances sharing the database skip their runs while another one is running the job,
+    /// and scheduled runs are skipped if another instance ran the job within its interval
+    pub fn add_exclusive_job<F, Fut>(&mut self, name: &'static str, interval: Duration, run: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = JobOutcome> + Send + 'static,
+    {
+        self.push_job(name, interval, true, run);
+    }
+
+    fn push_job<F, Fut>(&mut self, name: &'static str, interval: Duration, exclusive: bool, run: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = JobOutcome> + Send + 'static,
+    {
+        self.jobs.push(Arc::new(Job {
+            name,
+            interval,
+            exclusive,
+            run: Box::new(move || Box::pin(run())),
+            trigger: Notify::new(),
+            state: Mutex::new(JobState::default()),
+        }));
+    }
+
+    /// Returns the state of every job, in the order they were added
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.jobs.iter().map(|job| job.status()).collect()
+    }
+
+    /// Runs a job as soon as possible, or right after its current run if it is running
+    /// Returns `false` if there is no job called `name`
+    pub fn trigger(&self, name: &str) -> bool {
+        match self.jobs.iter().find(|job| job.name == name) {
+            Some(job) => {
+                job.trigger.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn tasks(&self) -> MutexGuard<'_, Vec<JoinHandle<()>>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Takes the job's advisory lock and claims the run if it needs to
+/// Runs that were triggered on demand go ahead even if the job ran recently
+/// Returns `Ok(None)` for jobs that don't, and `Err` with the result to record if the run is off
+async fn lock_job(job: &Job, triggered: bool) -> Result<Option<db::AdvisoryLock>, JobResult> {
+    if !job.exclusive {
+        return Ok(None);
+    }
+
+    let lock = match db::try_job_lock(job.name).await {
+        Ok(Some(lock)) => lock,
+        Ok(None) => {
+            info!("Skipping {}, another instance is running it", job.name);
+            return Err(JobResult::Skipped(
+                "Another instance is running the job".to_string(),
+            ));
+        }
+        Err(err) => {
+            error!("{}", err);
+            return Err(JobResult::Error(err));
+        }
+    };
+
+    // Every instance schedules the job from its own liftoff, so their runs would only rarely
+    // overlap; checking when the job last ran keeps them from each running it once per interval
+    // A tenth of the interval is left as slack, so this instance's own schedule isn't skipped
+    let gap = (!triggered).then(|| job.interval - job.interval / 10);
+    match lock.claim_run(job.name, gap).await {
+        Ok(Some(lock)) => Ok(Some(lock)),
+        Ok(None) => {
+            info!("Skipping {}, another instance ran it recently", job.name);
+            Err(JobResult::Skipped(
+                "Another instance ran the job recently".to_string(),
+            ))
+        }
+        Err(err) => {
+            error!("{}", err);
+            Err(JobResult::Error(err))
+        }
+    }
+}
+
+/// Runs a job on its schedule and whenever it is triggered, until shutdown is signalled
+/// A run still in progress at shutdown is cancelled
+async fn run_job(job: Arc<Job>, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = time::interval(job.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let triggered = rocket::tokio::select! {
+            _ = interval.tick() => false,
+            _ = job.trigger.notified() => {
+                info!("Running {} on demand", job.name);
+                // Count the schedule from this run, so the job doesn't run twice in a row
+                interval.reset();
+                true
+            }
+            _ = shutdown.changed() => break,
+        };
+
+        let started = Instant::now();
+        {
+            let mut state = job.state();
+            state.running = true;
+            state.last_run = Some(Utc::now());
+        }
+
+        // Held until the end of the run, or dropped along with it on shutdown
+        let _lock = match lock_job(&job, triggered).await {
+            Ok(lock) => lock,
+            Err(result) => {
+                let mut state = job.state();
+                state.running = false;
+                state.last_duration = Some(started.elapsed());
+                state.last_result = Some(result);
+                continue;
+            }
+        };
+
+        let outcome = rocket::tokio::select! {
+            outcome = (job.run)() => outcome,
+            _ = shutdown.changed() => {
+                warn!("{} was interrupted by shutdown", job.name);
+                job.state().running = false;
+                break;
+            }
+        };
+
+        let duration = started.elapsed();
+        match &outcome {
+            Ok(summary) => info!("{} finished in {:?}: {}", job.name, duration, summary),
+            Err(err) => error!("{} failed after {:?}: {}", job.name, duration, err),
+        }
+
+        let mut state = job.state();
+        state.running = false;
+        state.last_duration = Some(duration);
+        state.last_result = Some(outcome.into());
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Maintenance {
+    fn info(&self) -> Info {
+        Info {
+            name: "Maintenance jobs",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, _rocket: &Rocket<Orbit>) {
+        let mut tasks = self.tasks();
+        for job in &self.jobs {
+            info!("Scheduling {} every {:?}", job.name, job.interval);
+            tasks.push(rocket::tokio::spawn(run_job(
+                job.clone(),
+                self.shutdown.subscribe(),
+            )));
+        }
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        self.shutdown.send_replace(true);
+
+        let tasks = std::mem::take(&mut *self.tasks());
+        for task in tasks {
+            if let Err(e) = task.await {
+                error!("Maintenance job task failed: {}", e);
+            }
+        }
+    }
+}
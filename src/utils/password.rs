@@ -0,0 +1,67 @@
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+
+use std::time::Duration;
+
+use super::rate_limit::RateLimitConfig;
+
+/// The header clients can use to send a clip's password instead of the query string
+pub const PASSWORD_HEADER: &str = "X-Clip-Password";
+
+/// Wrong password attempts allowed per clip, across all clients
+pub const PASSWORD_ATTEMPTS: RateLimitConfig =
+    RateLimitConfig::new(Duration::from_secs(15 * 60), 10);
+
+fn hash_blocking(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_blocking(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            error!("Invalid password hash: {}", e);
+            false
+        }
+    }
+}
+
+/// Hashes a clip password for storage
+/// Argon2 takes tens of milliseconds by design, so it runs on a blocking thread
+pub async fn hash_password(password: String) -> Result<String, String> {
+    rocket::tokio::task::spawn_blocking(move || {
+        hash_blocking(&password).map_err(|e| format!("Failed to hash password: {}", e))
+    })
+    .await
+    .map_err(|e| format!("Password hashing task failed: {}", e))?
+}
+
+/// Checks a password against a hash produced by `hash_password`, on a blocking thread
+pub async fn verify_password(password: String, hash: String) -> bool {
+    rocket::tokio::task::spawn_blocking(move || verify_blocking(&password, &hash))
+        .await
+        .unwrap_or_else(|e| {
+            error!("Password verification task failed: {}", e);
+            false
+        })
+}
+
+/// The clip password sent in the `X-Clip-Password` header, if any
+pub struct PasswordHeader(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PasswordHeader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let password = request.headers().get_one(PASSWORD_HEADER);
+        Outcome::Success(PasswordHeader(password.map(str::to_string)))
+    }
+}
@@ -1,68 +1,365 @@
+use base64ct::{Base64UrlUnpadded, Encoding};
+use blake2::{Blake2s256, Digest};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
 use rocket::request::{self, FromRequest, Outcome};
+use rocket::{Request, Response};
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU32, Ordering};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
 
 extern crate serde;
 extern crate serde_json;
 
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use async_lock::RwLock;
 
+use super::auth::api_key;
+
+pub mod store;
+
+use store::RateLimitStore;
+
+/// Where rate limiting buckets are kept, read from `rate_limit.store` in `Rocket.toml`
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    /// Each instance counts its own requests
+    #[default]
+    Memory,
+    /// Buckets are shared by all instances through the database
+    Postgres,
+}
+
+/// How requests are counted against a bucket
+#[derive(Clone)]
+pub enum RateLimitAlgorithm {
+    /// Counts requests in consecutive windows; allows up to 2x bursts across window boundaries
+    FixedWindow,
+    /// Remembers the time of every request made within the last interval
+    SlidingLog,
+    /// Refills `max_requests` tokens per interval, holding at most `burst` of them
+    TokenBucket { burst: u32 },
+}
+
 #[derive(Clone)]
 pub struct RateLimitConfig {
     interval: Duration,
     max_requests: u32,
+    algorithm: RateLimitAlgorithm,
 }
 
 impl RateLimitConfig {
-    pub fn new(interval: Duration, max_requests: u32) -> Self {
+    /// Creates a fixed window config allowing `max_requests` per `interval`
+    pub const fn new(interval: Duration, max_requests: u32) -> Self {
         RateLimitConfig {
             interval,
             max_requests,
+            algorithm: RateLimitAlgorithm::FixedWindow,
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: RateLimitAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Tokens added per second by the token bucket
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.max_requests) / self.interval.as_secs_f64()
+    }
+
+    /// How long an untouched bucket takes to return to its initial state
+    fn idle_after(&self) -> Duration {
+        match self.algorithm {
+            RateLimitAlgorithm::FixedWindow | RateLimitAlgorithm::SlidingLog => self.interval,
+            RateLimitAlgorithm::TokenBucket { burst } => {
+                Duration::from_secs_f64(f64::from(burst) / self.refill_rate())
+            }
         }
     }
 }
 
+/// Who a request is counted against
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ClientId {
+    ApiKey(String),
+    Ip(IpAddr),
+    Unknown,
+    /// Counts requests from all clients together
+    Everyone,
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Shown and stored as a hash, so the key itself doesn't end up in the database
+            ClientId::ApiKey(key) => write!(
+                f,
+                "key:{}",
+                Base64UrlUnpadded::encode_string(&Blake2s256::digest(key))
+            ),
+            ClientId::Ip(ip) => write!(f, "ip:{}", ip),
+            ClientId::Unknown => write!(f, "unknown"),
+            ClientId::Everyone => write!(f, "everyone"),
+        }
+    }
+}
+
+impl ClientId {
+    /// Identifies the client by its API key if it presented a valid one, by its IP otherwise
+    /// The IP is the peer's address unless a trusted proxy header is configured, see `ip_header`
+    pub fn from_request(request: &rocket::Request<'_>) -> Self {
+        if let Some(key) = api_key(request) {
+            return ClientId::ApiKey(key.to_string());
+        }
+
+        match request.client_ip() {
+            Some(ip) => ClientId::Ip(client_network(ip)),
+            None => ClientId::Unknown,
+        }
+    }
+}
+
+/// The address requests from `ip` are counted against
+/// IPv6 clients usually get a whole /64, so they are grouped by it rather than by address
+fn client_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+/// The outcome of counting a request against its bucket
+#[derive(Clone)]
+pub struct RateLimitDecision {
+    pub limited: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the full quota is available again
+    pub reset_after: Duration,
+    /// Time until the next request will be allowed
+    pub retry_after: Duration,
+}
+
+/// Time elapsed between two wall clock readings, zero if the clock went backwards
+fn elapsed(now: SystemTime, since: SystemTime) -> Duration {
+    now.duration_since(since).unwrap_or_default()
+}
+
+// Buckets use wall clock time so they can be shared between instances
+#[derive(Serialize, Deserialize)]
+enum BucketState {
+    FixedWindow {
+        requests: u32,
+        reset_time: SystemTime,
+    },
+    SlidingLog {
+        requests: VecDeque<SystemTime>,
+    },
+    TokenBucket {
+        tokens: f64,
+        last_refill: SystemTime,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    state: BucketState,
+    last_seen: SystemTime,
+    idle_after: Duration,
+}
+
+impl Bucket {
+    fn new(config: &RateLimitConfig) -> Self {
+        let now = SystemTime::now();
+        let state = match config.algorithm {
+            RateLimitAlgorithm::FixedWindow => BucketState::FixedWindow {
+                requests: 0,
+                reset_time: now,
+            },
+            RateLimitAlgorithm::SlidingLog => BucketState::SlidingLog {
+                requests: VecDeque::new(),
+            },
+            RateLimitAlgorithm::TokenBucket { burst } => BucketState::TokenBucket {
+                tokens: f64::from(burst),
+                last_refill: now,
+            },
+        };
+
+        Bucket {
+            state,
+            last_seen: now,
+            idle_after: config.idle_after(),
+        }
+    }
+
+    /// Checks the bucket and, if `record` is set, counts a request unless it is already exhausted
+    fn check(&mut self, config: &RateLimitConfig, record: bool) -> RateLimitDecision {
+        let now = SystemTime::now();
+        if record {
+            self.last_seen = now;
+        }
+
+        match (&mut self.state, &config.algorithm) {
+            (
+                BucketState::FixedWindow {
+                    requests,
+                    reset_time,
+                },
+                RateLimitAlgorithm::FixedWindow,
+            ) => {
+                if elapsed(now, *reset_time) >= config.interval {
+                    *reset_time = now;
+                    *requests = 0;
+                }
+
+                let limited = *requests >= config.max_requests;
+                if !limited && record {
+                    *requests += 1;
+                }
+
+                let reset_after = config.interval.saturating_sub(elapsed(now, *reset_time));
+                RateLimitDecision {
+                    limited,
+                    limit: config.max_requests,
+                    remaining: config.max_requests.saturating_sub(*requests),
+                    reset_after,
+                    retry_after: reset_after,
+                }
+            }
+            (BucketState::SlidingLog { requests }, RateLimitAlgorithm::SlidingLog) => {
+                while requests
+                    .front()
+                    .is_some_and(|time| elapsed(now, *time) >= config.interval)
+                {
+                    requests.pop_front();
+                }
+
+                let limited = requests.len() >= config.max_requests as usize;
+                if !limited && record {
+                    requests.push_back(now);
+                }
+
+                let expires_after =
+                    |time: &SystemTime| config.interval.saturating_sub(elapsed(now, *time));
+                RateLimitDecision {
+                    limited,
+                    limit: config.max_requests,
+                    remaining: config.max_requests.saturating_sub(requests.len() as u32),
+                    reset_after: requests.back().map(expires_after).unwrap_or_default(),
+                    retry_after: requests.front().map(expires_after).unwrap_or_default(),
+                }
+            }
+            (
+                BucketState::TokenBucket {
+                    tokens,
+                    last_refill,
+                },
+                RateLimitAlgorithm::TokenBucket { burst },
+            ) => {
+                let rate = config.refill_rate();
+                let burst = f64::from(*burst);
+                *tokens = (*tokens + elapsed(now, *last_refill).as_secs_f64() * rate).min(burst);
+                *last_refill = now;
+
+                let limited = *tokens < 1.0;
+                if !limited && record {
+                    *tokens -= 1.0;
+                }
+
+                RateLimitDecision {
+                    limited,
+                    limit: burst as u32,
+                    remaining: *tokens as u32,
+                    reset_after: Duration::from_secs_f64((burst - *tokens) / rate),
+                    retry_after: Duration::from_secs_f64((1.0 - *tokens).max(0.0) / rate),
+                }
+            }
+            // The config for this route changed since the bucket was created
+            _ => {
+                *self = Bucket::new(config);
+                self.check(config, record)
+            }
+        }
+    }
+
+    /// Whether the bucket is back in its initial state and can be dropped
+    fn is_idle(&self) -> bool {
+        elapsed(SystemTime::now(), self.last_seen) >= self.idle_after
+    }
+}
+
 #[derive(Clone)]
 pub struct RateLimiter {
-    requests: Arc<AtomicU32>,
-    reset_time: Arc<RwLock<Instant>>,
+    store: Arc<dyn RateLimitStore>,
     config: Arc<RwLock<HashMap<String, RateLimitConfig>>>,
 }
 
 impl RateLimiter {
-    /// Create a new RateLimiter
-    pub fn new() -> Self {
+    /// Create a new RateLimiter keeping its buckets in `store`
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
         RateLimiter {
-            requests: Arc::new(AtomicU32::new(0)),
-            reset_time: Arc::new(RwLock::new(Instant::now())),
+            store,
             config: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Sets the limit for a route, e.g. `/api/clip` or `/api/file/<code>`,
+    /// or for one method on it, e.g. `POST /api/clip`
     pub async fn add_config(&self, path: &str, config: RateLimitConfig) {
         let mut configs = self.config.write().await;
         configs.insert(path.to_string(), config);
     }
 
-    async fn should_limit(&self, interval: Duration, max_requests: u32) -> bool {
-        let mut reset_time = self.reset_time.write().await;
-        let requests = self.requests.load(Ordering::Relaxed);
+    async fn should_limit(
+        &self,
+        client: ClientId,
+        route: String,
+        config: &RateLimitConfig,
+    ) -> Option<RateLimitDecision> {
+        self.record_attempt(&client, &route, config).await
+    }
 
-        if reset_time.elapsed() < interval {
-            if requests < max_requests {
-                self.requests.fetch_add(1, Ordering::Relaxed);
-                false
-            } else {
-                true
+    /// Counts an attempt against `key`, which may be a route or anything else worth limiting
+    pub async fn record_attempt(
+        &self,
+        client: &ClientId,
+        key: &str,
+        config: &RateLimitConfig,
+    ) -> Option<RateLimitDecision> {
+        match self.store.hit(client, key, config).await {
+            Ok(decision) => Some(decision),
+            Err(err) => {
+                // Better to let requests through than to take the service down with the store
+                error!("{}", err);
+                None
+            }
+        }
+    }
+
+    /// Returns the decision if a further attempt against `key` would be limited,
+    /// without counting one
+    pub async fn check_limited(
+        &self,
+        client: &ClientId,
+        key: &str,
+        config: &RateLimitConfig,
+    ) -> Option<RateLimitDecision> {
+        match self.store.peek(client, key, config).await {
+            Ok(decision) => decision.limited.then_some(decision),
+            Err(err) => {
+                error!("{}", err);
+                None
             }
-        } else {
-            *reset_time = Instant::now();
-            self.requests.store(1, Ordering::Relaxed);
-            false
         }
     }
 }
@@ -77,23 +374,244 @@ impl<'r> FromRequest<'r> for RateLimiter {
             .state::<RateLimiter>()
             .expect("RateLimiter registered as state");
 
-        let uri = request.uri();
-        let path = uri.path().to_string();
+        // Requests are counted against the route they matched, so `/api/file/<code>`
+        // shares one bucket across all codes instead of getting a fresh one per code
+        let path = match request.route() {
+            Some(route) => route.uri.path().to_string(),
+            None => request.uri().path().to_string(),
+        };
+        let method_path = format!("{} {}", request.method(), path);
 
-        let config = {
+        // A config for the method and path takes precedence over one for the path alone
+        let (route, config) = {
             let configs = rate_limiter.config.read().await;
-            configs.get(&path).cloned().unwrap_or_else(
-                || RateLimitConfig::new(Duration::from_secs(60), 20), // By default, allow 20 requests per minute
-            )
+            match configs.get(&method_path) {
+                Some(config) => (method_path, config.clone()),
+                None => {
+                    let config = configs.get(&path).cloned().unwrap_or_else(
+                        || RateLimitConfig::new(Duration::from_secs(60), 20), // By default, allow 20 requests per minute
+                    );
+                    (path, config)
+                }
+            }
         };
 
-        if rate_limiter
-            .should_limit(config.interval, config.max_requests)
-            .await
-        {
-            Outcome::Error((rocket::http::Status::TooManyRequests, ()))
+        let client = ClientId::from_request(request);
+
+        let decision = rate_limiter.should_limit(client, route, &config).await;
+        let limited = decision.as_ref().is_some_and(|decision| decision.limited);
+        request.local_cache(|| decision);
+
+        if limited {
+            Outcome::Error((Status::TooManyRequests, ()))
         } else {
             Outcome::Success(rate_limiter.clone())
         }
     }
 }
+
+/// A limit a handler ran into on its own, which the response headers describe instead of the route's
+struct ReportedDecision(Mutex<Option<RateLimitDecision>>);
+
+/// A request guard for reporting limits other than the route's, e.g. on password attempts,
+/// so the 429 responses they cause carry a matching `Retry-After`
+pub struct LimitReport<'r>(&'r Mutex<Option<RateLimitDecision>>);
+
+impl LimitReport<'_> {
+    pub fn report(&self, decision: RateLimitDecision) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(decision);
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LimitReport<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> request::Outcome<Self, ()> {
+        let reported = request.local_cache(|| ReportedDecision(Mutex::new(None)));
+        Outcome::Success(LimitReport(&reported.0))
+    }
+}
+
+/// Adds `X-RateLimit-*` headers to rate-limited responses and `Retry-After` to 429s
+pub struct RateLimitHeaders;
+
+#[rocket::async_trait]
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let reported = request.local_cache(|| ReportedDecision(Mutex::new(None)));
+        let reported = reported.0.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let decision = match reported.or_else(|| request.local_cache(|| None).clone()) {
+            Some(decision) => decision,
+            None => return,
+        };
+
+        let reset_secs = ceil_secs(decision.reset_after);
+
+        response.set_header(Header::new("X-RateLimit-Limit", decision.limit.to_string()));
+        response.set_header(Header::new(
+            "X-RateLimit-Remaining",
+            decision.remaining.to_string(),
+        ));
+        response.set_header(Header::new("X-RateLimit-Reset", reset_secs.to_string()));
+
+        if decision.limited {
+            response.set_header(Header::new(
+                "Retry-After",
+                ceil_secs(decision.retry_after).to_string(),
+            ));
+        }
+    }
+}
+
+/// Rounds up so clients never retry before the limit has actually reset
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(bucket: &mut Bucket, config: &RateLimitConfig, count: usize) -> Vec<bool> {
+        (0..count)
+            .map(|_| bucket.check(config, true).limited)
+            .collect()
+    }
+
+    /// Moves every timestamp of the bucket `by` into the past
+    fn rewind(bucket: &mut Bucket, by: Duration) {
+        match &mut bucket.state {
+            BucketState::FixedWindow { reset_time, .. } => *reset_time -= by,
+            BucketState::SlidingLog { requests } => {
+                requests.iter_mut().for_each(|time| *time -= by)
+            }
+            BucketState::TokenBucket { last_refill, .. } => *last_refill -= by,
+        }
+        bucket.last_seen -= by;
+    }
+
+    #[test]
+    fn fixed_window_limits_and_resets() {
+        let config = RateLimitConfig::new(Duration::from_secs(60), 3);
+        let mut bucket = Bucket::new(&config);
+
+        assert_eq!(hits(&mut bucket, &config, 4), [false, false, false, true]);
+        let decision = bucket.check(&config, true);
+        assert!(decision.limited);
+        assert_eq!(decision.remaining, 0);
+        assert!(decision.retry_after <= Duration::from_secs(60));
+
+        rewind(&mut bucket, Duration::from_secs(60));
+        assert!(!bucket.check(&config, true).limited);
+    }
+
+    #[test]
+    fn sliding_log_limits_and_expires_requests() {
+        let config = RateLimitConfig::new(Duration::from_secs(60), 2)
+            .with_algorithm(RateLimitAlgorithm::SlidingLog);
+        let mut bucket = Bucket::new(&config);
+
+        assert_eq!(hits(&mut bucket, &config, 3), [false, false, true]);
+
+        rewind(&mut bucket, Duration::from_secs(30));
+        assert!(bucket.check(&config, true).limited);
+
+        rewind(&mut bucket, Duration::from_secs(30));
+        let decision = bucket.check(&config, true);
+        assert!(!decision.limited);
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn token_bucket_allows_bursts_and_refills() {
+        // One token a second, up to 3 at once
+        let config = RateLimitConfig::new(Duration::from_secs(1), 1)
+            .with_algorithm(RateLimitAlgorithm::TokenBucket { burst: 3 });
+        let mut bucket = Bucket::new(&config);
+
+        assert_eq!(hits(&mut bucket, &config, 4), [false, false, false, true]);
+        let decision = bucket.check(&config, false);
+        assert!(decision.retry_after <= Duration::from_secs(1));
+        assert!(decision.reset_after <= Duration::from_secs(3));
+
+        rewind(&mut bucket, Duration::from_secs(1));
+        assert_eq!(hits(&mut bucket, &config, 2), [false, true]);
+
+        // Refills never go beyond the burst
+        rewind(&mut bucket, Duration::from_secs(60));
+        assert_eq!(bucket.check(&config, false).remaining, 3);
+    }
+
+    #[test]
+    fn peeking_does_not_count() {
+        let config = RateLimitConfig::new(Duration::from_secs(60), 1);
+        let mut bucket = Bucket::new(&config);
+
+        for _ in 0..5 {
+            assert!(!bucket.check(&config, false).limited);
+        }
+        assert!(!bucket.check(&config, true).limited);
+        assert!(bucket.check(&config, false).limited);
+    }
+
+    #[test]
+    fn changed_algorithm_starts_a_new_bucket() {
+        let config = RateLimitConfig::new(Duration::from_secs(60), 1);
+        let mut bucket = Bucket::new(&config);
+        assert_eq!(hits(&mut bucket, &config, 2), [false, true]);
+
+        let config = config.with_algorithm(RateLimitAlgorithm::SlidingLog);
+        assert!(!bucket.check(&config, true).limited);
+        assert!(matches!(bucket.state, BucketState::SlidingLog { .. }));
+    }
+
+    #[test]
+    fn buckets_become_idle() {
+        let config = RateLimitConfig::new(Duration::from_secs(60), 1);
+        let mut bucket = Bucket::new(&config);
+        bucket.check(&config, true);
+        assert!(!bucket.is_idle());
+
+        rewind(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.is_idle());
+    }
+
+    #[test]
+    fn ipv6_clients_are_grouped_by_network() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            client_network(ip("2001:db8:1:2:aaaa:bbbb:cccc:dddd")),
+            ip("2001:db8:1:2::")
+        );
+        assert_eq!(
+            client_network(ip("2001:db8:1:2::1")),
+            client_network(ip("2001:db8:1:2::2"))
+        );
+        assert_ne!(
+            client_network(ip("2001:db8:1:2::1")),
+            client_network(ip("2001:db8:1:3::1"))
+        );
+        assert_eq!(client_network(ip("203.0.113.7")), ip("203.0.113.7"));
+        assert_eq!(client_network(ip("::ffff:203.0.113.7")), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn api_keys_are_not_shown() {
+        let client = ClientId::ApiKey("secret-key".to_string());
+        let shown = client.to_string();
+        assert!(shown.starts_with("key:"));
+        assert!(!shown.contains("secret-key"));
+        assert_eq!(
+            shown,
+            ClientId::ApiKey("secret-key".to_string()).to_string()
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use crate::models::RateLimitBucket;
+use crate::schema::rate_limit_buckets;
+use crate::utils::db::DbPool;
+
+use diesel::prelude::*;
+use diesel::result::Error;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_lock::{Mutex, RwLock};
+use chrono::NaiveDateTime;
+
+use super::{Bucket, ClientId, RateLimitConfig, RateLimitDecision};
+
+/// How often idle buckets are dropped
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+/// Most buckets kept in memory, beyond which new clients share one bucket per route
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+
+/// Keeps the rate limiting buckets of every client and route
+#[rocket::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Counts a request from `client` against its bucket for `route`
+    async fn hit(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+    ) -> Result<RateLimitDecision, String>;
+
+    /// Checks the bucket of `client` for `route` without counting a request
+    async fn peek(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+    ) -> Result<RateLimitDecision, String>;
+}
+
+struct Buckets {
+    entries: HashMap<(ClientId, String), Bucket>,
+    last_eviction: Instant,
+}
+
+/// Keeps buckets in this instance's memory
+pub struct MemoryStore {
+    buckets: RwLock<Buckets>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            buckets: RwLock::new(Buckets {
+                entries: HashMap::new(),
+                last_eviction: Instant::now(),
+            }),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn hit(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+    ) -> Result<RateLimitDecision, String> {
+        let mut buckets = self.buckets.write().await;
+
+        let mut key = (client.clone(), route.to_string());
+        let is_new = !buckets.entries.contains_key(&key);
+        let is_full = buckets.entries.len() >= MAX_MEMORY_BUCKETS;
+        if buckets.last_eviction.elapsed() >= EVICTION_INTERVAL || (is_new && is_full) {
+            buckets.entries.retain(|_, bucket| !bucket.is_idle());
+            buckets.last_eviction = Instant::now();
+        }
+
+        // Memory stays bounded however many addresses a client spreads its requests over
+        if is_new && buckets.entries.len() >= MAX_MEMORY_BUCKETS {
+            key = (ClientId::Everyone, route.to_string());
+        }
+
+        Ok(buckets
+            .entries
+            .entry(key)
+            .or_insert_with(|| Bucket::new(config))
+            .check(config, true))
+    }
+
+    async fn peek(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+    ) -> Result<RateLimitDecision, String> {
+        let mut buckets = self.buckets.write().await;
+
+        let key = (client.clone(), route.to_string());
+        Ok(match buckets.entries.get_mut(&key) {
+            Some(bucket) => bucket.check(config, false),
+            None => Bucket::new(config).check(config, false),
+        })
+    }
+}
+
+/// Keeps buckets in the database so that all instances share them
+pub struct PostgresStore {
+    pool: DbPool,
+    last_eviction: Mutex<Instant>,
+}
+
+impl PostgresStore {
+    pub fn new(pool: DbPool) -> Self {
+        PostgresStore {
+            pool,
+            last_eviction: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+/// The time after which a bucket can be dropped, in the database's clock convention
+fn bucket_expiry(bucket: &Bucket) -> NaiveDateTime {
+    chrono::DateTime::<chrono::Local>::from(bucket.last_seen + bucket.idle_after).naive_local()
+}
+
+fn serialize_bucket(bucket: &Bucket) -> Result<String, Error> {
+    serde_json::to_string(bucket).map_err(|e| Error::SerializationError(Box::new(e)))
+}
+
+/// Counts a request against the bucket stored under `key`, locking its row for the update
+fn hit_bucket(
+    connection: &mut PgConnection,
+    key: &str,
+    config: &RateLimitConfig,
+) -> Result<RateLimitDecision, Error> {
+    connection.transaction(|connection| {
+        let fresh = Bucket::new(config);
+        diesel::insert_into(rate_limit_buckets::table)
+            .values(&RateLimitBucket {
+                key: key.to_string(),
+                state: serialize_bucket(&fresh)?,
+                expires_at: bucket_expiry(&fresh),
+            })
+            .on_conflict_do_nothing()
+            .execute(connection)?;
+
+        let stored = rate_limit_buckets::table
+            .find(key)
+            .for_update()
+            .first::<RateLimitBucket>(connection)?;
+
+        // A bucket written by an incompatible version is simply started over
+        let mut bucket = serde_json::from_str::<Bucket>(&stored.state).unwrap_or(fresh);
+        let decision = bucket.check(config, true);
+
+        diesel::update(rate_limit_buckets::table.find(key))
+            .set((
+                rate_limit_buckets::state.eq(serialize_bucket(&bucket)?),
+                rate_limit_buckets::expires_at.eq(bucket_expiry(&bucket)),
+            ))
+            .execute(connection)?;
+
+        Ok(decision)
+    })
+}
+
+/// Checks the bucket stored under `key` without counting a request
+fn peek_bucket(
+    connection: &mut PgConnection,
+    key: &str,
+    config: &RateLimitConfig,
+) -> Result<RateLimitDecision, Error> {
+    let stored = rate_limit_buckets::table
+        .find(key)
+        .first::<RateLimitBucket>(connection)
+        .optional()?;
+
+    let mut bucket = stored
+        .and_then(|stored| serde_json::from_str::<Bucket>(&stored.state).ok())
+        .unwrap_or_else(|| Bucket::new(config));
+
+    Ok(bucket.check(config, false))
+}
+
+/// Deletes buckets that are back in their initial state
+fn evict_idle_buckets(connection: &mut PgConnection) -> Result<usize, Error> {
+    use crate::schema::rate_limit_buckets::dsl::*;
+
+    diesel::delete(rate_limit_buckets.filter(expires_at.lt(chrono::Local::now().naive_local())))
+        .execute(connection)
+}
+
+impl PostgresStore {
+    /// Runs `operation` on the bucket of `client` for `route` on a blocking thread
+    async fn with_bucket(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+        operation: fn(
+            &mut PgConnection,
+            &str,
+            &RateLimitConfig,
+        ) -> Result<RateLimitDecision, Error>,
+    ) -> Result<RateLimitDecision, String> {
+        let evict = {
+            let mut last_eviction = self.last_eviction.lock().await;
+            let evict = last_eviction.elapsed() >= EVICTION_INTERVAL;
+            if evict {
+                *last_eviction = Instant::now();
+            }
+            evict
+        };
+
+        let pool = self.pool.clone();
+        let key = format!("{} {}", client, route);
+        let config = config.clone();
+
+        rocket::tokio::task::spawn_blocking(move || {
+            let mut connection = pool
+                .get()
+                .map_err(|e| format!("Failed to get a database connection: {}", e))?;
+
+            if evict {
+                evict_idle_buckets(&mut connection)
+                    .map_err(|e| format!("Failed to evict rate limit buckets: {}", e))?;
+            }
+
+            operation(&mut connection, &key, &config)
+                .map_err(|e| format!("Failed to access rate limit bucket: {}", e))
+        })
+        .await
+        .map_err(|e| format!("Rate limit task failed: {}", e))?
+    }
+}
+
+#[rocket::async_trait]
+impl RateLimitStore for PostgresStore {
+    async fn hit(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+    ) -> Result<RateLimitDecision, String> {
+        self.with_bucket(client, route, config, hit_bucket).await
+    }
+
+    async fn peek(
+        &self,
+        client: &ClientId,
+        route: &str,
+        config: &RateLimitConfig,
+    ) -> Result<RateLimitDecision, String> {
+        self.with_bucket(client, route, config, peek_bucket).await
+    }
+}
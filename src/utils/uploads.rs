@@ -0,0 +1,99 @@
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::Request;
+use serde::Deserialize;
+
+use super::auth::api_key;
+
+/// Smallest part S3 accepts, except for the last part of an upload
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024; // 5MiB
+/// Largest part S3 accepts
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024; // 5GiB
+/// Most parts a multipart upload can have
+pub const MAX_PARTS: u64 = 10_000;
+
+/// Upload limits for one tier of clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadLimits {
+    /// Largest file the tier can upload, in bytes
+    pub max_size: u64,
+}
+
+/// Upload settings, read from the `uploads` section of `Rocket.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Size of the parts multipart uploads are split into, in bytes
+    /// Grows for files that would otherwise need more than 10,000 parts
+    pub part_size: u64,
+    /// Limits for clients without an API key
+    pub anonymous: UploadLimits,
+    /// Limits for clients with a valid API key
+    pub authenticated: UploadLimits,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            part_size: 16 * 1024 * 1024,
+            anonymous: UploadLimits {
+                max_size: 100 * 1024 * 1024,
+            },
+            authenticated: UploadLimits {
+                max_size: 5 * 1024 * 1024 * 1024,
+            },
+        }
+    }
+}
+
+impl UploadConfig {
+    /// Checks that the settings are within what S3 supports
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&self.part_size) {
+            return Err(format!(
+                "The part size must be between {} and {} bytes",
+                MIN_PART_SIZE, MAX_PART_SIZE
+            ));
+        }
+        for limits in [&self.anonymous, &self.authenticated] {
+            if limits.max_size == 0 || limits.max_size > MAX_PART_SIZE * MAX_PARTS {
+                return Err(format!(
+                    "Upload size limits must be between 1 and {} bytes",
+                    MAX_PART_SIZE * MAX_PARTS
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The limits for a client, depending on whether it is authenticated
+    pub fn limits(&self, authenticated: bool) -> &UploadLimits {
+        if authenticated {
+            &self.authenticated
+        } else {
+            &self.anonymous
+        }
+    }
+
+    /// The part size to split a file of `size` bytes into
+    pub fn part_size_for(&self, size: u64) -> u64 {
+        self.part_size.max(size.div_ceil(MAX_PARTS))
+    }
+}
+
+/// A request guard for the upload limits of the requesting client
+pub struct ClientUploadLimits(pub UploadLimits);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientUploadLimits {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let config = request
+            .rocket()
+            .state::<UploadConfig>()
+            .expect("UploadConfig registered as state");
+
+        let limits = config.limits(api_key(request).is_some());
+        Outcome::Success(ClientUploadLimits(limits.clone()))
+    }
+}
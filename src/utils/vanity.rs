@@ -0,0 +1,152 @@
+use std::fmt;
+
+/// Codes that would be confusing or could be mistaken for parts of the service
+const RESERVED_CODES: &[&str] = &[
+    "about",
+    "admin",
+    "api",
+    "app",
+    "clip",
+    "clips",
+    "download",
+    "file",
+    "files",
+    "help",
+    "interclip",
+    "login",
+    "logout",
+    "new",
+    "privacy",
+    "settings",
+    "stats",
+    "status",
+    "support",
+    "terms",
+    "upload",
+    "version",
+    "www",
+];
+
+/// Words no code may contain
+const BLOCKED_WORDS: &[&str] = &[
+    "anal", "anus", "bitch", "boob", "cock", "cunt", "dick", "fag", "fuck", "nazi", "nigg",
+    "penis", "porn", "pussy", "rape", "shit", "slut", "twat", "whore",
+];
+
+#[derive(Debug)]
+pub enum VanityCodeError {
+    Reserved,
+    Inappropriate,
+}
+
+impl fmt::Display for VanityCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VanityCodeError::Reserved => write!(f, "This code is reserved"),
+            VanityCodeError::Inappropriate => write!(f, "This code is not allowed"),
+        }
+    }
+}
+
+/// Undoes the usual digit-for-letter substitutions, so they can't sneak blocked words through
+fn deobfuscate(code: &str) -> String {
+    code.chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            '0' => 'o',
+            '1' => 'i',
+            '3' => 'e',
+            '4' => 'a',
+            '5' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .collect()
+}
+
+/// Checks a code requested by a clip's creator against the reserved and blocked words
+/// `code` is what the creator sent, `normalize` brings codes into the form they are looked up in,
+/// so a code that only reads differently from a reserved one, e.g. `adm1n`, is reserved as well
+/// The code's format is checked separately, like that of any other code
+pub fn check_vanity_code(
+    code: &str,
+    normalize: impl Fn(&str) -> String,
+) -> Result<(), VanityCodeError> {
+    let lowercase = code.to_ascii_lowercase();
+    let plain = deobfuscate(code);
+    let normalized = normalize(code);
+    if RESERVED_CODES.iter().any(|reserved| {
+        *reserved == lowercase || *reserved == plain || normalize(reserved) == normalized
+    }) {
+        return Err(VanityCodeError::Reserved);
+    }
+
+    if BLOCKED_WORDS
+        .iter()
+        .any(|word| lowercase.contains(word) || plain.contains(word))
+    {
+        return Err(VanityCodeError::Inappropriate);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::id::{CodeConfig, CodeGenerator, CodeScheme};
+
+    fn generator(scheme: CodeScheme) -> CodeGenerator {
+        CodeGenerator::new(CodeConfig {
+            scheme,
+            ..CodeConfig::default()
+        })
+    }
+
+    fn check(code: &str, codes: &CodeGenerator) -> Result<(), VanityCodeError> {
+        check_vanity_code(code, |code| codes.normalize(code))
+    }
+
+    #[test]
+    fn reserved_codes_are_rejected_in_every_spelling() {
+        let codes = generator(CodeScheme::Crockford);
+        // Crockford reads `i` and `l` as `1`, so these all look up the same clip as `admin` or `login`
+        for code in [
+            "admin", "ADMIN", "adm1n", "admln", "login", "10g1n", "LOGIN", "he1p",
+        ] {
+            assert!(
+                matches!(check(code, &codes), Err(VanityCodeError::Reserved)),
+                "{:?}",
+                code
+            );
+        }
+
+        let codes = generator(CodeScheme::Alphanumeric);
+        for code in ["admin", "Admin", "adm1n", "h3lp"] {
+            assert!(
+                matches!(check(code, &codes), Err(VanityCodeError::Reserved)),
+                "{:?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn blocked_words_are_rejected() {
+        let codes = generator(CodeScheme::Alphanumeric);
+        for code in ["xfuckx", "sh1t", "PORN"] {
+            assert!(matches!(
+                check(code, &codes),
+                Err(VanityCodeError::Inappropriate)
+            ));
+        }
+    }
+
+    #[test]
+    fn other_codes_are_allowed() {
+        for scheme in [CodeScheme::Alphanumeric, CodeScheme::Crockford] {
+            let codes = generator(scheme);
+            assert!(check("meeting", &codes).is_ok());
+            assert!(check("party24", &codes).is_ok());
+        }
+    }
+}